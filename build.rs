@@ -0,0 +1,66 @@
+// Embeds the SQL files in migrations/ into the binary, see src/db/migrate.rs, and records
+// the commit it was built from for GET /status
+use std::path::Path;
+use std::process::Command;
+use std::{env, fs};
+
+fn main() {
+    embed_git_commit();
+    println!("cargo:rerun-if-changed=migrations");
+
+    let dir = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("migrations");
+    let mut migrations = Vec::new();
+    for entry in fs::read_dir(&dir).expect("migrations directory is missing") {
+        let file_name = entry.unwrap().file_name().into_string().unwrap();
+        // down scripts are for rolling back by hand with sqlx-cli, they are never run from here
+        let name = match file_name.strip_suffix(".up.sql") {
+            Some(name) => name,
+            None => continue,
+        };
+        let (version, description) = name
+            .split_once('_')
+            .unwrap_or_else(|| panic!("{} should be named <version>_<description>", file_name));
+        let version: i64 = version
+            .parse()
+            .unwrap_or_else(|_| panic!("{} doesn't start with a numeric version", file_name));
+        migrations.push((version, description.replace('_', " "), file_name));
+    }
+    migrations.sort();
+
+    let mut code = String::from("&[\n");
+    for (version, description, file_name) in migrations {
+        code.push_str(&format!(
+            "EmbeddedMigration {{ version: {}, description: {:?}, sql: include_str!({:?}) }},\n",
+            version,
+            description,
+            dir.join(file_name).display().to_string()
+        ));
+    }
+    code.push_str("]\n");
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("migrations.rs");
+    fs::write(out, code).unwrap();
+}
+
+// images are often built without .git, so GIT_COMMIT can be passed in instead
+fn embed_git_commit() {
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    if Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/refs");
+    }
+
+    let commit = env::var("GIT_COMMIT").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|commit| commit.trim().to_string())
+    });
+    println!(
+        "cargo:rustc-env=GIT_COMMIT={}",
+        commit.unwrap_or_else(|| "unknown".to_string())
+    );
+}
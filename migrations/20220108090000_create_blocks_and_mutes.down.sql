@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE mutes;
+DROP TABLE blocks;
\ No newline at end of file
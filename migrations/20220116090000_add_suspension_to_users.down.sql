@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE users
+DROP COLUMN disabled_reason,
+DROP COLUMN disabled_at;
\ No newline at end of file
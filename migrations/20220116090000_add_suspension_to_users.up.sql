@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE users
+ADD COLUMN disabled_at TIMESTAMPTZ,
+ADD COLUMN disabled_reason TEXT;
@@ -0,0 +1,8 @@
+-- Add up migration script here
+CREATE TABLE reset_codes (
+  id UUID PRIMARY KEY,
+  code_hash TEXT NOT NULL UNIQUE,
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  expires_at TIMESTAMPTZ NOT NULL,
+  used_at TIMESTAMPTZ
+);
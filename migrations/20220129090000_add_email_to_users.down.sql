@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TABLE email_verifications;
+
+ALTER TABLE users
+DROP COLUMN email_verified_at,
+DROP COLUMN email;
\ No newline at end of file
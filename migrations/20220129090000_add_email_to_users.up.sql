@@ -0,0 +1,12 @@
+-- Add up migration script here
+ALTER TABLE users
+ADD COLUMN email TEXT UNIQUE,
+ADD COLUMN email_verified_at TIMESTAMPTZ;
+
+CREATE TABLE email_verifications (
+  id UUID PRIMARY KEY,
+  code_hash TEXT NOT NULL UNIQUE,
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  email TEXT NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE login_challenges;
+DROP TABLE recovery_codes;
+DROP TABLE totp_credentials;
\ No newline at end of file
@@ -0,0 +1,21 @@
+-- Add up migration script here
+CREATE TABLE totp_credentials (
+  user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  secret TEXT NOT NULL,
+  enabled_at TIMESTAMPTZ,
+  last_used_step BIGINT
+);
+
+CREATE TABLE recovery_codes (
+  id UUID PRIMARY KEY,
+  code_hash TEXT NOT NULL,
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  used_at TIMESTAMPTZ
+);
+
+CREATE TABLE login_challenges (
+  id UUID PRIMARY KEY,
+  value_hash TEXT NOT NULL UNIQUE,
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  expires_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE lockout_events;
+DROP TABLE login_attempts;
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP TABLE oauth_access_tokens;
+DROP TABLE oauth_authorization_codes;
+DROP TABLE oauth_consents;
+DROP TABLE oauth_clients;
\ No newline at end of file
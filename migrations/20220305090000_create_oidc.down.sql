@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE oidc_login_states;
+DROP TABLE oidc_identities;
\ No newline at end of file
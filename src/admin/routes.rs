@@ -0,0 +1,210 @@
+use crate::audit::{self, AuditAction, AuditEvent, AuditFilter, AuditTarget};
+use crate::auth::{self, Permission, Scope};
+use crate::post::Post;
+use crate::user::{DisableRequest, User, UserAdmin};
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use log::error;
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(find_all_users)
+        .service(delete_post)
+        .service(disable_user)
+        .service(reinstate_user)
+        .service(find_audit_events);
+}
+
+#[get("/admin/users")]
+async fn find_all_users(credentials: BearerAuth, db_pool: web::Data<PgPool>) -> impl Responder {
+    let result = auth::validate_bearer_auth(credentials, Scope::Admin, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    if let Err(err) = auth::authorize(&user, Permission::ListAllUsers) {
+        return HttpResponse::from_error(err);
+    }
+
+    let result = User::find_all(db_pool.get_ref()).await;
+    match result {
+        Ok(users) => {
+            let users = users
+                .into_iter()
+                .map(UserAdmin::from)
+                .collect::<Vec<UserAdmin>>();
+            HttpResponse::Ok().json(users)
+        }
+        Err(err) => {
+            error!("error fetching users: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to read all users from database")
+        }
+    }
+}
+
+#[delete("/admin/posts/{id}")]
+async fn delete_post(
+    req: HttpRequest,
+    credentials: BearerAuth,
+    id: web::Path<Uuid>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result = auth::validate_bearer_auth(credentials, Scope::Admin, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    if let Err(err) = auth::authorize(&user, Permission::DeleteAnyPost) {
+        return HttpResponse::from_error(err);
+    }
+
+    let id = id.into_inner();
+    let result = Post::delete_any(id, db_pool.get_ref()).await;
+    match result {
+        Ok(rows_deleted) => {
+            if rows_deleted > 0 {
+                audit::record(
+                    &req,
+                    AuditAction::PostDeleted,
+                    Some(user.id),
+                    Some(AuditTarget::Post(id)),
+                    json!({ "moderation": true }),
+                    db_pool.get_ref(),
+                )
+                .await;
+                let msg = format!("Successfully deleted {} record(s)", rows_deleted);
+                HttpResponse::Ok().body(msg)
+            } else {
+                HttpResponse::NotFound().body("Post not found")
+            }
+        }
+        Err(err) => {
+            error!("error deleting post: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to delete post")
+        }
+    }
+}
+
+#[post("/admin/users/{id}/disable")]
+async fn disable_user(
+    req: HttpRequest,
+    credentials: BearerAuth,
+    id: web::Path<Uuid>,
+    request: web::Json<DisableRequest>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result = auth::validate_bearer_auth(credentials, Scope::Admin, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    if let Err(err) = auth::authorize(&user, Permission::DisableUser) {
+        return HttpResponse::from_error(err);
+    }
+
+    let target_id = id.into_inner();
+    if target_id == user.id {
+        return HttpResponse::BadRequest().body("You cannot disable your own account");
+    }
+
+    let result = User::disable(target_id, &request.reason, db_pool.get_ref()).await;
+    match result {
+        Ok(Some(target)) => {
+            audit::record(
+                &req,
+                AuditAction::UserSuspended,
+                Some(user.id),
+                Some(AuditTarget::User(target.id)),
+                json!({ "reason": request.reason }),
+                db_pool.get_ref(),
+            )
+            .await;
+            HttpResponse::Ok().json(UserAdmin::from(target))
+        }
+        Ok(None) => HttpResponse::NotFound().body("User not found or already disabled"),
+        Err(err) => {
+            error!("error disabling user: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to disable user")
+        }
+    }
+}
+
+#[post("/admin/users/{id}/reinstate")]
+async fn reinstate_user(
+    req: HttpRequest,
+    credentials: BearerAuth,
+    id: web::Path<Uuid>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result = auth::validate_bearer_auth(credentials, Scope::Admin, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    if let Err(err) = auth::authorize(&user, Permission::ReinstateUser) {
+        return HttpResponse::from_error(err);
+    }
+
+    let result = User::reinstate(id.into_inner(), db_pool.get_ref()).await;
+    match result {
+        Ok(Some(target)) => {
+            audit::record(
+                &req,
+                AuditAction::UserReinstated,
+                Some(user.id),
+                Some(AuditTarget::User(target.id)),
+                json!({}),
+                db_pool.get_ref(),
+            )
+            .await;
+            HttpResponse::Ok().json(UserAdmin::from(target))
+        }
+        Ok(None) => HttpResponse::NotFound().body("User not found or not disabled"),
+        Err(err) => {
+            error!("error reinstating user: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to reinstate user")
+        }
+    }
+}
+
+#[get("/admin/audit")]
+async fn find_audit_events(
+    credentials: BearerAuth,
+    filter: web::Query<AuditFilter>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result = auth::validate_bearer_auth(credentials, Scope::Admin, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    if let Err(err) = auth::authorize(&user, Permission::ViewAuditLog) {
+        return HttpResponse::from_error(err);
+    }
+
+    let result = AuditEvent::find(&filter, None, db_pool.get_ref()).await;
+    match result {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(err) => {
+            error!("error fetching audit events: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to read audit events")
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use crate::auth::{scopes_from_db, scopes_to_db, Scope};
+use crate::token::{generate_random_u8s, hash_secret};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Done, PgPool};
+use uuid::Uuid;
+
+// keys look like sk_<prefix>_<secret>; the marker lets secret scanners spot leaked keys and
+// lets the auth layer tell keys from login tokens, the prefix identifies the key without the secret
+const KEY_MARKER: &str = "sk_";
+// ten years; anything much larger would overflow chrono's date arithmetic
+pub const MAX_EXPIRES_IN_DAYS: i64 = 3650;
+
+#[derive(Serialize, Deserialize)]
+pub struct ApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+// the only time the full key is ever shown
+#[derive(Serialize)]
+pub struct NewApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+impl ApiKey {
+    pub fn is_api_key(token: &str) -> bool {
+        token.starts_with(KEY_MARKER)
+    }
+
+    pub async fn create(request: ApiKeyRequest, user_id: Uuid, pool: &PgPool) -> Result<NewApiKey> {
+        let prefix = base32::encode(
+            base32::Alphabet::RFC4648 { padding: false },
+            &generate_random_u8s(5),
+        )
+        .to_lowercase();
+        let secret = base64::encode_config(generate_random_u8s(32), base64::URL_SAFE_NO_PAD);
+        let key = format!("{}{}_{}", KEY_MARKER, prefix, secret);
+
+        let id = Uuid::new_v4();
+        let scopes = scopes_to_db(&request.scopes);
+        // checked by the route already, but chrono panics rather than erroring past its range
+        let expires_at = match request.expires_in_days {
+            Some(days) if (1..=MAX_EXPIRES_IN_DAYS).contains(&days) => {
+                Some(Utc::now() + Duration::days(days))
+            }
+            Some(days) => return Err(anyhow!("expires_in_days {} is out of range", days)),
+            None => None,
+        };
+
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO api_keys (id, user_id, name, prefix, key_hash, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+            id,
+            user_id,
+            request.name,
+            prefix,
+            hash_secret(&key),
+            &scopes,
+            expires_at,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        let rec = sqlx::query!(
+            r#"
+            SELECT id, user_id, name, prefix, scopes, created_at, last_used_at, expires_at
+            FROM api_keys
+            WHERE id = $1
+            "#,
+            id,
+        )
+        .fetch_one(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(NewApiKey {
+            api_key: ApiKey {
+                id: rec.id,
+                user_id: rec.user_id,
+                name: rec.name,
+                prefix: rec.prefix,
+                scopes: scopes_from_db(&rec.scopes),
+                created_at: rec.created_at,
+                last_used_at: rec.last_used_at,
+                expires_at: rec.expires_at,
+            },
+            key,
+        })
+    }
+
+    pub async fn find_by_user(user_id: Uuid, pool: &PgPool) -> Result<Vec<ApiKey>> {
+        let api_keys = sqlx::query!(
+            r#"
+            SELECT id, user_id, name, prefix, scopes, created_at, last_used_at, expires_at
+            FROM api_keys
+            WHERE user_id = $1
+            ORDER BY created_at
+            "#,
+            user_id,
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|rec| ApiKey {
+            id: rec.id,
+            user_id: rec.user_id,
+            name: rec.name,
+            prefix: rec.prefix,
+            scopes: scopes_from_db(&rec.scopes),
+            created_at: rec.created_at,
+            last_used_at: rec.last_used_at,
+            expires_at: rec.expires_at,
+        })
+        .collect();
+
+        Ok(api_keys)
+    }
+
+    pub async fn delete(id: Uuid, user_id: Uuid, pool: &PgPool) -> Result<u64> {
+        let mut tx = pool.begin().await?;
+
+        let n_deleted = sqlx::query!(
+            r#"
+            DELETE FROM api_keys
+            WHERE id = $1 AND user_id = $2
+            "#,
+            id,
+            user_id,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(n_deleted)
+    }
+
+    // returns the key if it exists and hasn't expired, and records that it was used
+    pub async fn authenticate(key: &str, pool: &PgPool) -> Result<Option<ApiKey>> {
+        let prefix = match key
+            .strip_prefix(KEY_MARKER)
+            .and_then(|rest| rest.split('_').next())
+        {
+            Some(prefix) => prefix,
+            None => return Ok(None),
+        };
+
+        let mut tx = pool.begin().await?;
+
+        let rec = sqlx::query!(
+            r#"
+            UPDATE api_keys
+            SET last_used_at = now()
+            WHERE prefix = $1 AND key_hash = $2
+            AND (expires_at IS NULL OR expires_at > now())
+            RETURNING id, user_id, name, prefix, scopes, created_at, last_used_at, expires_at
+            "#,
+            prefix,
+            hash_secret(key),
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(rec.map(|rec| ApiKey {
+            id: rec.id,
+            user_id: rec.user_id,
+            name: rec.name,
+            prefix: rec.prefix,
+            scopes: scopes_from_db(&rec.scopes),
+            created_at: rec.created_at,
+            last_used_at: rec.last_used_at,
+            expires_at: rec.expires_at,
+        }))
+    }
+}
@@ -0,0 +1,122 @@
+use crate::api_key::{ApiKey, ApiKeyRequest, MAX_EXPIRES_IN_DAYS};
+use crate::audit::{self, AuditAction};
+use crate::auth::{self, Scope};
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use log::error;
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(create).service(find_all).service(delete);
+}
+
+#[post("/users/api-keys")]
+async fn create(
+    req: HttpRequest,
+    credentials: BearerAuth,
+    request: web::Json<ApiKeyRequest>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result = auth::validate_bearer_auth(credentials, Scope::Account, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    if request.scopes.is_empty() {
+        return HttpResponse::BadRequest().body("An API key needs at least one scope");
+    }
+    if let Some(scope) = request.scopes.iter().find(|scope| !scope.is_delegable()) {
+        let msg = format!("The {} scope can't be granted to an API key", scope);
+        return HttpResponse::BadRequest().body(msg);
+    }
+    if let Some(days) = request.expires_in_days {
+        if !(1..=MAX_EXPIRES_IN_DAYS).contains(&days) {
+            let msg = format!(
+                "expires_in_days must be between 1 and {}",
+                MAX_EXPIRES_IN_DAYS
+            );
+            return HttpResponse::BadRequest().body(msg);
+        }
+    }
+
+    let result = ApiKey::create(request.into_inner(), user.id, db_pool.get_ref()).await;
+    match result {
+        Ok(api_key) => {
+            let details = json!({
+                "kind": "api_key",
+                "api_key_id": api_key.api_key.id,
+                "scopes": &api_key.api_key.scopes,
+            });
+            audit::record(
+                &req,
+                AuditAction::TokenCreated,
+                Some(user.id),
+                None,
+                details,
+                db_pool.get_ref(),
+            )
+            .await;
+            HttpResponse::Ok().json(api_key)
+        }
+        Err(err) => {
+            error!("error creating api key: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to create new API key")
+        }
+    }
+}
+
+#[get("/users/api-keys")]
+async fn find_all(credentials: BearerAuth, db_pool: web::Data<PgPool>) -> impl Responder {
+    let result = auth::validate_bearer_auth(credentials, Scope::Account, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    let result = ApiKey::find_by_user(user.id, db_pool.get_ref()).await;
+    match result {
+        Ok(api_keys) => HttpResponse::Ok().json(api_keys),
+        Err(err) => {
+            error!("error fetching api keys: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to read API keys from database")
+        }
+    }
+}
+
+#[delete("/users/api-keys/{id}")]
+async fn delete(
+    credentials: BearerAuth,
+    id: web::Path<Uuid>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result = auth::validate_bearer_auth(credentials, Scope::Account, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    let result = ApiKey::delete(id.into_inner(), user.id, db_pool.get_ref()).await;
+    match result {
+        Ok(rows_deleted) => {
+            if rows_deleted > 0 {
+                let msg = format!("Successfully deleted {} record(s)", rows_deleted);
+                HttpResponse::Ok().body(msg)
+            } else {
+                HttpResponse::NotFound().body("API key not found")
+            }
+        }
+        Err(err) => {
+            error!("error deleting api key: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to delete API key")
+        }
+    }
+}
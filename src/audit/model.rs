@@ -0,0 +1,220 @@
+use crate::metrics::Metrics;
+use actix_web::{web, HttpRequest};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum AuditAction {
+    #[serde(rename = "login")]
+    Login,
+    #[serde(rename = "login.failed")]
+    LoginFailed,
+    #[serde(rename = "password.changed")]
+    PasswordChanged,
+    #[serde(rename = "password.reset")]
+    PasswordReset,
+    // API keys and OAuth access tokens; logins note their session token on the login event
+    #[serde(rename = "token.created")]
+    TokenCreated,
+    #[serde(rename = "user.deleted")]
+    UserDeleted,
+    #[serde(rename = "user.suspended")]
+    UserSuspended,
+    #[serde(rename = "user.reinstated")]
+    UserReinstated,
+    #[serde(rename = "post.updated")]
+    PostUpdated,
+    #[serde(rename = "post.deleted")]
+    PostDeleted,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Login => "login",
+            AuditAction::LoginFailed => "login.failed",
+            AuditAction::PasswordChanged => "password.changed",
+            AuditAction::PasswordReset => "password.reset",
+            AuditAction::TokenCreated => "token.created",
+            AuditAction::UserDeleted => "user.deleted",
+            AuditAction::UserSuspended => "user.suspended",
+            AuditAction::UserReinstated => "user.reinstated",
+            AuditAction::PostUpdated => "post.updated",
+            AuditAction::PostDeleted => "post.deleted",
+        }
+    }
+}
+
+// what an event is about, when that isn't just the actor
+#[derive(Clone, Copy)]
+pub enum AuditTarget {
+    User(Uuid),
+    Post(Uuid),
+}
+
+impl AuditTarget {
+    fn kind(&self) -> &'static str {
+        match self {
+            AuditTarget::User(_) => "user",
+            AuditTarget::Post(_) => "post",
+        }
+    }
+
+    fn id(&self) -> Uuid {
+        match self {
+            AuditTarget::User(id) | AuditTarget::Post(id) => *id,
+        }
+    }
+}
+
+// query of GET /users/audit and GET /admin/audit, every filter is optional
+#[derive(Serialize, Deserialize)]
+pub struct AuditFilter {
+    pub action: Option<AuditAction>,
+    pub actor_id: Option<Uuid>,
+    pub target_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct AuditEvent {
+    pub id: Uuid,
+    pub action: String,
+    pub actor_id: Option<Uuid>,
+    pub target_type: Option<String>,
+    pub target_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Value,
+    pub created_at: DateTime<Utc>,
+}
+
+pub struct NewAuditEvent {
+    pub action: AuditAction,
+    pub actor_id: Option<Uuid>,
+    pub target: Option<AuditTarget>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Value,
+}
+
+impl AuditEvent {
+    // the table only takes inserts, a trigger refuses updates and deletes
+    pub async fn create(event: NewAuditEvent, pool: &PgPool) -> Result<()> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO audit_events
+                (id, action, actor_id, target_type, target_id, ip, user_agent, details)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            "#,
+            Uuid::new_v4(),
+            event.action.as_str(),
+            event.actor_id,
+            event.target.map(|target| target.kind()),
+            event.target.map(|target| target.id()),
+            event.ip,
+            event.user_agent,
+            event.details,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // newest first; involving narrows the result to events the user did or that were done to them
+    pub async fn find(
+        filter: &AuditFilter,
+        involving: Option<Uuid>,
+        pool: &PgPool,
+    ) -> Result<Vec<AuditEvent>> {
+        let limit = filter.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+        let recs = sqlx::query!(
+            r#"
+            SELECT id, action, actor_id, target_type, target_id, ip, user_agent, details,
+                created_at
+            FROM audit_events
+            WHERE ($1::uuid IS NULL OR actor_id = $1 OR target_id = $1)
+                AND ($2::text IS NULL OR action = $2)
+                AND ($3::uuid IS NULL OR actor_id = $3)
+                AND ($4::uuid IS NULL OR target_id = $4)
+                AND ($5::text IS NULL OR ip = $5)
+                AND ($6::timestamptz IS NULL OR created_at >= $6)
+                AND ($7::timestamptz IS NULL OR created_at < $7)
+            ORDER BY created_at DESC
+            LIMIT $8
+            "#,
+            involving,
+            filter.action.map(|action| action.as_str()),
+            filter.actor_id,
+            filter.target_id,
+            filter.ip.as_deref(),
+            filter.since,
+            filter.until,
+            limit,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(recs
+            .into_iter()
+            .map(|rec| AuditEvent {
+                id: rec.id,
+                action: rec.action,
+                actor_id: rec.actor_id,
+                target_type: rec.target_type,
+                target_id: rec.target_id,
+                ip: rec.ip,
+                user_agent: rec.user_agent,
+                details: rec.details,
+                created_at: rec.created_at,
+            })
+            .collect())
+    }
+}
+
+// auditing must never make the action itself fail, so errors are only logged
+pub async fn record(
+    req: &HttpRequest,
+    action: AuditAction,
+    actor_id: Option<Uuid>,
+    target: Option<AuditTarget>,
+    details: Value,
+    pool: &PgPool,
+) {
+    if let Some(metrics) = req.app_data::<web::Data<Metrics>>() {
+        metrics.observe_audit(action, &details);
+    }
+
+    let event = NewAuditEvent {
+        action,
+        actor_id,
+        target,
+        ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+        user_agent: req
+            .headers()
+            .get("user-agent")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string()),
+        details,
+    };
+    if let Err(err) = AuditEvent::create(event, pool).await {
+        error!("error recording audit event {}: {}", action.as_str(), err);
+    }
+}
@@ -0,0 +1,34 @@
+use crate::audit::{AuditEvent, AuditFilter};
+use crate::auth::{self, Scope};
+use actix_web::{get, web, HttpResponse, Responder};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use log::error;
+use sqlx::PgPool;
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(find_own);
+}
+
+#[get("/users/audit")]
+async fn find_own(
+    credentials: BearerAuth,
+    filter: web::Query<AuditFilter>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result = auth::validate_bearer_auth(credentials, Scope::Account, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    let result = AuditEvent::find(&filter, Some(user.id), db_pool.get_ref()).await;
+    match result {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(err) => {
+            error!("error fetching audit events: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to read audit events")
+        }
+    }
+}
@@ -1,12 +1,35 @@
+use crate::api_key::ApiKey;
+use crate::oauth::AccessToken;
+use crate::password::Passwords;
 use crate::user::User;
-use actix_web::Error;
+use actix_web::error::{ErrorForbidden, InternalError};
+use actix_web::http::header;
+use actix_web::{Error, HttpRequest, HttpResponse};
 use actix_web_httpauth::extractors::basic::{BasicAuth, Config as BasicConfig};
-use actix_web_httpauth::extractors::bearer::{BearerAuth, Config as BearerConfig};
+use actix_web_httpauth::extractors::bearer::{
+    BearerAuth, Config as BearerConfig, Error as BearerError,
+};
 use actix_web_httpauth::extractors::AuthenticationError;
-use bcrypt::verify;
+use chrono::Duration;
+use log::{error, warn};
 use sqlx::PgPool;
+use tracing::Span;
 
-pub async fn validate_basic_auth(credentials: BasicAuth, pool: &PgPool) -> Result<User, Error> {
+mod permission;
+mod scope;
+mod throttle;
+
+pub use permission::*;
+pub use scope::*;
+pub use throttle::LoginThrottle;
+
+pub async fn validate_basic_auth(
+    credentials: BasicAuth,
+    req: &HttpRequest,
+    throttle: &LoginThrottle,
+    passwords: &Passwords,
+    pool: &PgPool,
+) -> Result<User, Error> {
     let config = BasicConfig::default();
 
     let password = match credentials.password() {
@@ -16,26 +39,207 @@ pub async fn validate_basic_auth(credentials: BasicAuth, pool: &PgPool) -> Resul
         }
     };
 
+    // failures count against the username and the client address alike; the address is the
+    // TCP peer, so behind a reverse proxy every client shares the proxy's and its allowance
+    let mut keys = vec![format!("user:{}", credentials.user_id())];
+    if let Some(addr) = req.peer_addr() {
+        keys.push(format!("ip:{}", addr.ip()));
+    }
+
+    // a broken store shouldn't lock everybody out, so it is only logged
+    match throttle.locked_for(&keys).await {
+        Ok(Some(remaining)) => return Err(too_many_attempts(remaining)),
+        Ok(None) => {}
+        Err(err) => error!("error reading login attempts: {}", err),
+    }
+
+    // every way of failing costs one hash verification and ends in the same 401,
+    // so response times don't tell unknown usernames apart from wrong passwords
     let result = User::find_by_username(credentials.user_id(), pool).await;
-    match result {
-        Ok(Some(user)) => {
-            let valid = verify(password.as_bytes(), &user.password).unwrap_or(false);
-            if valid {
-                Ok(user)
-            } else {
-                Err(AuthenticationError::from(config).into())
+    let verified = match result {
+        Ok(Some(user)) => match passwords.verify(password, &user.password).await {
+            Ok(verification) if verification.valid => Some((user, verification)),
+            Ok(_) => None,
+            Err(err) => {
+                error!("error verifying password: {}", err);
+                None
             }
+        },
+        Ok(None) => {
+            if let Err(err) = passwords.verify_dummy(password).await {
+                error!("error verifying password: {}", err);
+            }
+            None
         }
-        // TODO: return 500 error when Err?
-        Ok(None) | Err(_) => Err(AuthenticationError::from(config).into()),
+        Err(err) => {
+            error!("error fetching user: {}", err);
+            if let Err(err) = passwords.verify_dummy(password).await {
+                error!("error verifying password: {}", err);
+            }
+            None
+        }
+    };
+
+    let (user, verification) = match verified {
+        Some(verified) => verified,
+        None => {
+            record_failure(&keys, throttle, pool).await;
+            return Err(AuthenticationError::from(config).into());
+        }
+    };
+
+    // the password is at hand only now, so this is the moment to move it to the current hasher
+    if verification.needs_rehash {
+        if let Err(err) = User::rehash_password(user.id, password, passwords, pool).await {
+            error!("error rehashing password: {}", err);
+        }
+    }
+
+    // the address keeps its count, or one valid account would let it guess at every other one;
+    // it is scored against a higher threshold instead, see throttle::lockout_for
+    if let Err(err) = throttle.reset(&keys[0]).await {
+        error!("error resetting login attempts: {}", err);
     }
+
+    ensure_not_suspended(user)
+}
+
+// login tokens may do anything their user may do, API keys only what their scopes allow
+pub async fn validate_bearer_auth(
+    credentials: BearerAuth,
+    scope: Scope,
+    pool: &PgPool,
+) -> Result<User, Error> {
+    let grant = authenticate_bearer(credentials, pool).await?;
+    grant.require(scope)?;
+    Ok(grant.user)
 }
 
-pub async fn validate_bearer_auth(credentials: BearerAuth, pool: &PgPool) -> Result<User, Error> {
+// a bearer credential resolved to its user, for handlers that need more than one scope
+pub struct BearerGrant {
+    pub user: User,
+    // None for login tokens, which carry every scope
+    scopes: Option<Vec<Scope>>,
+}
+
+impl BearerGrant {
+    pub fn require(&self, scope: Scope) -> Result<(), Error> {
+        if self
+            .scopes
+            .as_ref()
+            .is_none_or(|scopes| scopes.contains(&scope))
+        {
+            return Ok(());
+        }
+        let config = BearerConfig::default().scope(scope.to_string());
+        Err(AuthenticationError::from(config)
+            .with_error(BearerError::InsufficientScope)
+            .into())
+    }
+}
+
+pub async fn authenticate_bearer(
+    credentials: BearerAuth,
+    pool: &PgPool,
+) -> Result<BearerGrant, Error> {
     let config = BearerConfig::default();
-    let result = User::find_by_token(credentials.token(), pool).await;
+    let token = credentials.token();
+
+    // login tokens carry every scope, API keys and OAuth access tokens only the delegated ones
+    let delegated = if ApiKey::is_api_key(token) {
+        ApiKey::authenticate(token, pool)
+            .await
+            .map(|api_key| api_key.map(|api_key| (api_key.user_id, api_key.scopes)))
+    } else if AccessToken::is_access_token(token) {
+        AccessToken::find_by_value(token, pool)
+            .await
+            .map(|access_token| {
+                access_token
+                    .filter(|access_token| access_token.is_active())
+                    .map(|access_token| (access_token.user_id, access_token.scopes))
+            })
+    } else {
+        let result = User::find_by_token(token, pool).await;
+        return match result {
+            Ok(Some(user)) => {
+                ensure_not_suspended(user).map(|user| BearerGrant { user, scopes: None })
+            }
+            Ok(None) | Err(_) => Err(AuthenticationError::from(config).into()),
+        };
+    };
+
+    let (user_id, scopes) = match delegated {
+        Ok(Some(delegated)) => delegated,
+        Ok(None) | Err(_) => return Err(AuthenticationError::from(config).into()),
+    };
+
+    let result = User::find_by_id(user_id, pool).await;
     match result {
-        Ok(Some(user)) => Ok(user),
+        Ok(Some(user)) => ensure_not_suspended(user).map(|user| BearerGrant {
+            user,
+            scopes: Some(scopes),
+        }),
         Ok(None) | Err(_) => Err(AuthenticationError::from(config).into()),
     }
 }
+
+// for endpoints that cost something on every call; each one counts like a failed login
+// against the client address
+pub async fn throttle_address(
+    req: &HttpRequest,
+    throttle: &LoginThrottle,
+    pool: &PgPool,
+) -> Result<(), Error> {
+    let keys = match req.peer_addr() {
+        Some(addr) => vec![format!("ip:{}", addr.ip())],
+        None => return Ok(()),
+    };
+
+    match throttle.locked_for(&keys).await {
+        Ok(Some(remaining)) => return Err(too_many_attempts(remaining)),
+        Ok(None) => {}
+        Err(err) => error!("error reading login attempts: {}", err),
+    }
+    record_failure(&keys, throttle, pool).await;
+    Ok(())
+}
+
+pub fn ensure_not_suspended(user: User) -> Result<User, Error> {
+    if user.disabled_at.is_none() {
+        // every way of authenticating ends here, see telemetry::RequestTracing
+        Span::current().record("user_id", &user.id.to_string().as_str());
+        return Ok(user);
+    }
+
+    let msg = match user.disabled_reason {
+        Some(reason) => format!("Account is suspended: {}", reason),
+        None => "Account is suspended".to_string(),
+    };
+    Err(ErrorForbidden(msg))
+}
+
+async fn record_failure(keys: &[String], throttle: &LoginThrottle, pool: &PgPool) {
+    for key in keys {
+        match throttle.record_failure(key).await {
+            Ok(Some(lockout)) => {
+                warn!("locking out {} for {}s", key, lockout.num_seconds());
+                if let Err(err) = throttle::record_lockout(key, lockout, pool).await {
+                    error!("error recording lockout: {}", err);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => error!("error recording login attempt: {}", err),
+        }
+    }
+}
+
+fn too_many_attempts(remaining: Duration) -> Error {
+    let seconds = remaining.num_seconds().max(1);
+    let response = HttpResponse::TooManyRequests()
+        .header(header::RETRY_AFTER, seconds.to_string())
+        .body(format!(
+            "Too many failed login attempts, try again in {} seconds",
+            seconds
+        ));
+    InternalError::from_response("too many failed login attempts", response).into()
+}
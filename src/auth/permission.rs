@@ -0,0 +1,29 @@
+use crate::user::{Role, User};
+use actix_web::error::ErrorForbidden;
+use actix_web::Error;
+
+// actions that go beyond what an owner may do with their own resources
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Permission {
+    ListAllUsers,
+    DeleteAnyPost,
+    DisableUser,
+    ReinstateUser,
+    ViewAuditLog,
+}
+
+fn is_granted(role: Role, permission: Permission) -> bool {
+    match role {
+        Role::Admin => true,
+        Role::Moderator => matches!(permission, Permission::DeleteAnyPost),
+        Role::User => false,
+    }
+}
+
+pub fn authorize(user: &User, permission: Permission) -> Result<(), Error> {
+    if is_granted(user.role, permission) {
+        Ok(())
+    } else {
+        Err(ErrorForbidden("You are not allowed to perform this action"))
+    }
+}
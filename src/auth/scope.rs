@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// what a bearer credential may be used for; login tokens carry every scope,
+// API keys and OAuth access tokens only the ones they were granted
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scope {
+    #[serde(rename = "posts:read")]
+    PostsRead,
+    #[serde(rename = "posts:write")]
+    PostsWrite,
+    #[serde(rename = "users:write")]
+    UsersWrite,
+    // managing credentials (2FA, API keys, OAuth consent); never delegated
+    #[serde(rename = "account")]
+    Account,
+    // admin routes; never delegated
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::PostsRead => "posts:read",
+            Scope::PostsWrite => "posts:write",
+            Scope::UsersWrite => "users:write",
+            Scope::Account => "account",
+            Scope::Admin => "admin",
+        }
+    }
+
+    // whether API keys and OAuth clients may hold this scope; one that could mint credentials
+    // or reach admin routes would be as good as a password
+    pub fn is_delegable(&self) -> bool {
+        !matches!(self, Scope::Account | Scope::Admin)
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Scope, ()> {
+        match value {
+            "posts:read" => Ok(Scope::PostsRead),
+            "posts:write" => Ok(Scope::PostsWrite),
+            "users:write" => Ok(Scope::UsersWrite),
+            "account" => Ok(Scope::Account),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+// the space separated list OAuth uses, unknown scopes make the whole list invalid
+pub fn parse_scope_list(value: &str) -> Option<Vec<Scope>> {
+    value
+        .split_whitespace()
+        .map(|scope| scope.parse().ok())
+        .collect()
+}
+
+pub fn format_scope_list(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+pub fn scopes_to_db(scopes: &[Scope]) -> Vec<String> {
+    scopes.iter().map(|scope| scope.to_string()).collect()
+}
+
+// scopes that are no longer known are dropped rather than failing the whole credential
+pub fn scopes_from_db(scopes: &[String]) -> Vec<Scope> {
+    scopes
+        .iter()
+        .filter_map(|scope| scope.parse().ok())
+        .collect()
+}
@@ -0,0 +1,307 @@
+use crate::config::ThrottleStore;
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+// failures allowed before any lockout kicks in
+const FREE_ATTEMPTS: i32 = 5;
+// an address may be shared by a whole office behind NAT, so it gets more room than a username
+const FREE_ATTEMPTS_PER_ADDRESS: i32 = 50;
+// first lockout, doubled with every further failure
+const BASE_LOCKOUT_SECONDS: i64 = 1;
+const MAX_LOCKOUT_SECONDS: i64 = 15 * 60;
+// failures older than this no longer count
+const FAILURE_WINDOW_SECONDS: i64 = 60 * 60;
+// the in-process store sweeps stale entries once it holds this many keys
+const MEMORY_STORE_SWEEP_AT: usize = 10_000;
+
+pub struct Attempts {
+    failures: i32,
+    last_failure_at: DateTime<Utc>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+// counts failed logins per key (username or client address) and locks the key out with
+// exponential backoff; the in-process store is the default, the postgres store shares the
+// counters between instances
+pub enum LoginThrottle {
+    Memory(Mutex<HashMap<String, Attempts>>),
+    Postgres(PgPool),
+}
+
+impl LoginThrottle {
+    pub fn new(store: ThrottleStore, pool: &PgPool) -> LoginThrottle {
+        match store {
+            ThrottleStore::Postgres => LoginThrottle::Postgres(pool.clone()),
+            ThrottleStore::Memory => LoginThrottle::Memory(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // how much longer the most restricted of the keys stays locked
+    pub async fn locked_for(&self, keys: &[String]) -> Result<Option<Duration>> {
+        let now = Utc::now();
+        let mut locked_until: Option<DateTime<Utc>> = None;
+
+        for key in keys {
+            let until = match self {
+                LoginThrottle::Memory(store) => {
+                    let store = store.lock().unwrap();
+                    store.get(key).and_then(|attempts| attempts.locked_until)
+                }
+                LoginThrottle::Postgres(pool) => sqlx::query!(
+                    r#"
+                    SELECT locked_until
+                    FROM login_attempts
+                    WHERE key = $1
+                    "#,
+                    key,
+                )
+                .fetch_optional(pool)
+                .await?
+                .and_then(|rec| rec.locked_until),
+            };
+            locked_until = locked_until.max(until);
+        }
+
+        Ok(locked_until
+            .filter(|until| *until > now)
+            .map(|until| until - now))
+    }
+
+    // returns the lockout this failure started, if any
+    pub async fn record_failure(&self, key: &str) -> Result<Option<Duration>> {
+        let now = Utc::now();
+        let window_start = now - Duration::seconds(FAILURE_WINDOW_SECONDS);
+
+        let failures = match self {
+            LoginThrottle::Memory(store) => {
+                let mut store = store.lock().unwrap();
+                if store.len() >= MEMORY_STORE_SWEEP_AT {
+                    store.retain(|_, attempts| attempts.last_failure_at > window_start);
+                }
+
+                let attempts = store.entry(key.to_string()).or_insert(Attempts {
+                    failures: 0,
+                    last_failure_at: now,
+                    locked_until: None,
+                });
+                if attempts.last_failure_at < window_start {
+                    attempts.failures = 0;
+                }
+                attempts.failures += 1;
+                attempts.last_failure_at = now;
+                attempts.failures
+            }
+            LoginThrottle::Postgres(pool) => {
+                sqlx::query!(
+                    r#"
+                    INSERT INTO login_attempts (key, failures, last_failure_at)
+                    VALUES ($1, 1, $2)
+                    ON CONFLICT (key) DO UPDATE
+                    SET failures = CASE
+                            WHEN login_attempts.last_failure_at < $3 THEN 1
+                            ELSE login_attempts.failures + 1
+                        END,
+                        last_failure_at = EXCLUDED.last_failure_at
+                    RETURNING failures
+                    "#,
+                    key,
+                    now,
+                    window_start,
+                )
+                .fetch_one(pool)
+                .await?
+                .failures
+            }
+        };
+
+        let lockout = match lockout_for(key, failures) {
+            Some(lockout) => lockout,
+            None => return Ok(None),
+        };
+        let locked_until = now + lockout;
+
+        match self {
+            LoginThrottle::Memory(store) => {
+                let mut store = store.lock().unwrap();
+                if let Some(attempts) = store.get_mut(key) {
+                    attempts.locked_until = Some(locked_until);
+                }
+            }
+            LoginThrottle::Postgres(pool) => {
+                sqlx::query!(
+                    r#"
+                    UPDATE login_attempts
+                    SET locked_until = $1
+                    WHERE key = $2
+                    "#,
+                    locked_until,
+                    key,
+                )
+                .execute(pool)
+                .await?;
+            }
+        }
+
+        Ok(Some(lockout))
+    }
+
+    pub async fn reset(&self, key: &str) -> Result<()> {
+        match self {
+            LoginThrottle::Memory(store) => {
+                store.lock().unwrap().remove(key);
+            }
+            LoginThrottle::Postgres(pool) => {
+                sqlx::query!(
+                    r#"
+                    DELETE FROM login_attempts
+                    WHERE key = $1
+                    "#,
+                    key,
+                )
+                .execute(pool)
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// lockouts are always kept in postgres, whichever store holds the counters
+pub async fn record_lockout(key: &str, lockout: Duration, pool: &PgPool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO lockout_events (id, key, locked_until)
+        VALUES ($1, $2, $3)
+        "#,
+        Uuid::new_v4(),
+        key,
+        Utc::now() + lockout,
+    )
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+fn lockout_for(key: &str, failures: i32) -> Option<Duration> {
+    let free_attempts = if key.starts_with("ip:") {
+        FREE_ATTEMPTS_PER_ADDRESS
+    } else {
+        FREE_ATTEMPTS
+    };
+    if failures < free_attempts {
+        return None;
+    }
+
+    // capping the exponent keeps the shift from overflowing on very long attacks
+    let exponent = (failures - free_attempts).min(20) as u32;
+    let seconds = (BASE_LOCKOUT_SECONDS << exponent).min(MAX_LOCKOUT_SECONDS);
+    Some(Duration::seconds(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory() -> LoginThrottle {
+        LoginThrottle::Memory(Mutex::new(HashMap::new()))
+    }
+
+    fn failures(throttle: &LoginThrottle, key: &str) -> Option<i32> {
+        match throttle {
+            LoginThrottle::Memory(store) => store.lock().unwrap().get(key).map(|a| a.failures),
+            LoginThrottle::Postgres(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn lockout_doubles_after_the_free_attempts() {
+        assert_eq!(lockout_for("user:alice", FREE_ATTEMPTS - 1), None);
+        assert_eq!(
+            lockout_for("user:alice", FREE_ATTEMPTS),
+            Some(Duration::seconds(1))
+        );
+        assert_eq!(
+            lockout_for("user:alice", FREE_ATTEMPTS + 3),
+            Some(Duration::seconds(8))
+        );
+        assert_eq!(
+            lockout_for("user:alice", i32::MAX),
+            Some(Duration::seconds(MAX_LOCKOUT_SECONDS))
+        );
+    }
+
+    #[test]
+    fn addresses_get_more_free_attempts() {
+        assert_eq!(lockout_for("ip:192.0.2.1", FREE_ATTEMPTS), None);
+        assert_eq!(
+            lockout_for("ip:192.0.2.1", FREE_ATTEMPTS_PER_ADDRESS - 1),
+            None
+        );
+        assert_eq!(
+            lockout_for("ip:192.0.2.1", FREE_ATTEMPTS_PER_ADDRESS),
+            Some(Duration::seconds(1))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn memory_store_locks_out_after_the_free_attempts() {
+        let throttle = memory();
+        let keys = ["user:alice".to_string()];
+
+        for _ in 1..FREE_ATTEMPTS {
+            assert_eq!(throttle.record_failure(&keys[0]).await.unwrap(), None);
+        }
+        assert_eq!(throttle.locked_for(&keys).await.unwrap(), None);
+
+        let lockout = throttle.record_failure(&keys[0]).await.unwrap();
+        assert_eq!(lockout, Some(Duration::seconds(1)));
+        assert!(throttle.locked_for(&keys).await.unwrap().is_some());
+    }
+
+    #[actix_rt::test]
+    async fn memory_store_forgets_failures_outside_the_window() {
+        let throttle = memory();
+        let key = "user:alice";
+        if let LoginThrottle::Memory(store) = &throttle {
+            let stale = Utc::now() - Duration::seconds(FAILURE_WINDOW_SECONDS + 1);
+            store.lock().unwrap().insert(
+                key.to_string(),
+                Attempts {
+                    failures: FREE_ATTEMPTS + 3,
+                    last_failure_at: stale,
+                    locked_until: None,
+                },
+            );
+        }
+
+        assert_eq!(throttle.record_failure(key).await.unwrap(), None);
+        assert_eq!(failures(&throttle, key), Some(1));
+    }
+
+    #[actix_rt::test]
+    async fn memory_store_reset_clears_only_that_key() {
+        let throttle = memory();
+        let keys = ["user:alice".to_string(), "ip:192.0.2.1".to_string()];
+        let (user, ip) = (&keys[0], &keys[1]);
+        for _ in 0..FREE_ATTEMPTS {
+            throttle.record_failure(user).await.unwrap();
+            throttle.record_failure(ip).await.unwrap();
+        }
+        assert!(throttle.locked_for(&keys[..1]).await.unwrap().is_some());
+
+        throttle.reset(user).await.unwrap();
+        assert_eq!(throttle.locked_for(&keys[..1]).await.unwrap(), None);
+        assert_eq!(failures(&throttle, user), None);
+        assert_eq!(failures(&throttle, ip), Some(FREE_ATTEMPTS));
+    }
+}
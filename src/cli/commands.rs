@@ -0,0 +1,145 @@
+use super::{CreateUser, DbCommand, TokenCommand, UserCommand};
+use crate::audit::{AuditAction, AuditEvent, AuditTarget, NewAuditEvent};
+use crate::db::migrate;
+use crate::password::Passwords;
+use crate::token::Token;
+use crate::user::{Role, User, UserPostRequest};
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::io::{self, BufRead};
+use uuid::Uuid;
+
+pub async fn user(command: UserCommand, passwords: &Passwords, pool: &PgPool) -> Result<()> {
+    match command {
+        UserCommand::Create(request) => create_user(request, passwords, pool).await,
+        UserCommand::SetPassword { username } => {
+            let user = find_user(&username, pool).await?;
+            let password = read_password()?;
+            User::update_password(user.id, &password, passwords, pool)
+                .await?
+                .ok_or_else(|| anyhow!("user {} vanished", username))?;
+            // same as a reset, whoever knew the old password shouldn't stay signed in
+            let n_deleted = Token::delete_by_user(user.id, pool).await?;
+            record(AuditAction::PasswordReset, user.id, json!({}), pool).await?;
+            println!("password updated, {} session(s) revoked", n_deleted);
+            Ok(())
+        }
+        UserCommand::Disable { username, reason } => {
+            let user = find_user(&username, pool).await?;
+            match User::disable(user.id, &reason, pool).await? {
+                Some(_) => {
+                    let details = json!({ "reason": reason });
+                    record(AuditAction::UserSuspended, user.id, details, pool).await?;
+                    println!("disabled {}", username);
+                }
+                None => println!("{} was already disabled", username),
+            }
+            Ok(())
+        }
+    }
+}
+
+pub async fn token(command: TokenCommand, pool: &PgPool) -> Result<()> {
+    match command {
+        TokenCommand::Revoke { username } => {
+            let user = find_user(&username, pool).await?;
+            let n_deleted = Token::delete_by_user(user.id, pool).await?;
+            println!("revoked {} session(s)", n_deleted);
+            Ok(())
+        }
+    }
+}
+
+pub async fn db(command: DbCommand, pool: &PgPool) -> Result<()> {
+    match command {
+        DbCommand::Migrate => {
+            let n_applied = migrate::run(pool).await?;
+            println!("applied {} migration(s)", n_applied);
+        }
+        DbCommand::Status => {
+            for status in migrate::status(pool).await? {
+                let installed_on = status
+                    .installed_on
+                    .map(|installed_on| installed_on.to_rfc3339())
+                    .unwrap_or_default();
+                println!(
+                    "{:<16} {:<9} {:<32} {}",
+                    status.version,
+                    status.state.as_str(),
+                    installed_on,
+                    status.description
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn create_user(request: CreateUser, passwords: &Passwords, pool: &PgPool) -> Result<()> {
+    if User::find_by_username(&request.username, pool)
+        .await?
+        .is_some()
+    {
+        return Err(anyhow!("username {} is taken", request.username));
+    }
+    let password = read_password()?;
+    let new_user = UserPostRequest {
+        name: request.name,
+        username: request.username,
+        password,
+        email: request.email,
+    };
+    let mut user = User::create(new_user, passwords, pool).await?;
+
+    let role = Role::from_db(&request.role);
+    if role != user.role {
+        user = User::set_role(user.id, role, pool)
+            .await?
+            .ok_or_else(|| anyhow!("user {} vanished", user.username))?;
+    }
+
+    println!(
+        "created {} ({}) as {}",
+        user.username,
+        user.id,
+        user.role.as_str()
+    );
+    Ok(())
+}
+
+async fn find_user(username: &str, pool: &PgPool) -> Result<User> {
+    User::find_by_username(username, pool)
+        .await?
+        .ok_or_else(|| anyhow!("no user named {}", username))
+}
+
+// one line, so it can be piped in without ending up in the shell history
+fn read_password() -> Result<String> {
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]).to_string();
+    if password.is_empty() {
+        return Err(anyhow!("expected the password on stdin"));
+    }
+    Ok(password)
+}
+
+// nobody is signed in on the command line, so there is no actor, address or user agent
+async fn record(
+    action: AuditAction,
+    user_id: Uuid,
+    mut details: Value,
+    pool: &PgPool,
+) -> Result<()> {
+    details["source"] = json!("cli");
+    let event = NewAuditEvent {
+        action,
+        actor_id: None,
+        target: Some(AuditTarget::User(user_id)),
+        ip: None,
+        user_agent: None,
+        details,
+    };
+    AuditEvent::create(event, pool).await
+}
@@ -0,0 +1,104 @@
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+pub mod commands;
+
+// values stay text here so that bad ones are reported together with every other
+// configuration problem, see Config::load
+#[derive(Parser)]
+#[clap(version, about)]
+pub struct Cli {
+    /// TOML configuration file, defaults to ./config.toml when that exists
+    #[clap(long, short, env = "CONFIG_FILE", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Override any setting, e.g. --set database.max_connections=20
+    #[clap(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+
+    /// Address to listen on
+    #[clap(long, global = true)]
+    pub host: Option<String>,
+
+    /// Port to listen on
+    #[clap(long, global = true)]
+    pub port: Option<String>,
+
+    /// Postgres connection URL
+    #[clap(long, global = true)]
+    pub database_url: Option<String>,
+
+    /// Log output, text or json
+    #[clap(long, global = true)]
+    pub log_format: Option<String>,
+
+    /// Apply pending migrations before serving
+    #[clap(long, global = true)]
+    pub migrate: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+// without a command the server starts
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start the HTTP server
+    Serve,
+    /// Manage user accounts
+    #[clap(subcommand)]
+    User(UserCommand),
+    /// Manage session tokens
+    #[clap(subcommand)]
+    Token(TokenCommand),
+    /// Manage the database schema
+    #[clap(subcommand)]
+    Db(DbCommand),
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Create an account, the password is read from stdin
+    Create(CreateUser),
+    /// Replace a password read from stdin and sign the user out everywhere
+    SetPassword {
+        #[clap(long)]
+        username: String,
+    },
+    /// Suspend an account, its sessions are refused until it is reinstated
+    Disable {
+        #[clap(long)]
+        username: String,
+        #[clap(long)]
+        reason: String,
+    },
+}
+
+#[derive(Args)]
+pub struct CreateUser {
+    #[clap(long)]
+    pub username: String,
+    #[clap(long)]
+    pub name: String,
+    #[clap(long)]
+    pub email: Option<String>,
+    #[clap(long, default_value = "user", possible_values = ["user", "moderator", "admin"])]
+    pub role: String,
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    /// Delete every session token of a user
+    Revoke {
+        #[clap(long = "user", value_name = "USERNAME")]
+        username: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Apply every pending migration embedded in this binary
+    Migrate,
+    /// List applied and pending migrations
+    Status,
+}
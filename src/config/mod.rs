@@ -0,0 +1,914 @@
+// Typed settings for the whole service. Each layer overrides the one before it:
+//
+//   1. built-in defaults
+//   2. the TOML file given with --config (or CONFIG_FILE), else ./config.toml if it exists
+//   3. environment variables, see ENV_VARS
+//   4. command line flags, --set key=value first and then the dedicated flags like --port
+//      and --migrate
+//
+// Every problem is collected and reported at once, before anything starts.
+use crate::cli::Cli;
+use chrono::Duration;
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, fs, thread};
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+// the variables the service has always understood, mapped onto their settings
+#[rustfmt::skip]
+const ENV_VARS: &[(&str, &str)] = &[
+    ("APP_ENV", "server.environment"),
+    ("HOST", "server.host"),
+    ("PORT", "server.port"),
+    ("WORKERS", "server.workers"),
+    ("SHUTDOWN_TIMEOUT_SECONDS", "server.shutdown_timeout_seconds"),
+    ("DATABASE_URL", "database.url"),
+    ("DATABASE_MAX_CONNECTIONS", "database.max_connections"),
+    ("DATABASE_MIN_CONNECTIONS", "database.min_connections"),
+    ("DATABASE_ACQUIRE_TIMEOUT_SECONDS", "database.acquire_timeout_seconds"),
+    ("DATABASE_IDLE_TIMEOUT_SECONDS", "database.idle_timeout_seconds"),
+    ("DATABASE_MAX_LIFETIME_SECONDS", "database.max_lifetime_seconds"),
+    ("DATABASE_STATEMENT_TIMEOUT_MS", "database.statement_timeout_ms"),
+    ("DATABASE_APPLICATION_NAME", "database.application_name"),
+    ("DATABASE_SLOW_ACQUIRE_MS", "database.slow_acquire_ms"),
+    ("DATABASE_SATURATION_CHECK_SECONDS", "database.saturation_check_seconds"),
+    ("MIGRATE_ON_STARTUP", "database.migrate_on_startup"),
+    ("LOG_LEVEL", "log.level"),
+    ("LOG_FORMAT", "log.format"),
+    ("TRACING_EXPORTER", "tracing.exporter"),
+    ("OTEL_EXPORTER_OTLP_ENDPOINT", "tracing.otlp_endpoint"),
+    ("TRACING_FILE", "tracing.file"),
+    ("OTEL_SERVICE_NAME", "tracing.service_name"),
+    ("CORS_ALLOWED_ORIGINS", "cors.allowed_origins"),
+    ("CORS_ALLOWED_METHODS", "cors.allowed_methods"),
+    ("CORS_ALLOWED_HEADERS", "cors.allowed_headers"),
+    ("CORS_ALLOW_CREDENTIALS", "cors.allow_credentials"),
+    ("CORS_MAX_AGE_SECONDS", "cors.max_age_seconds"),
+    ("RESET_CODE_TTL_MINUTES", "tokens.reset_code_ttl_minutes"),
+    ("EMAIL_VERIFICATION_TTL_HOURS", "tokens.email_verification_ttl_hours"),
+    ("LOGIN_CHALLENGE_TTL_MINUTES", "tokens.login_challenge_ttl_minutes"),
+    ("OAUTH_CODE_TTL_MINUTES", "tokens.oauth_code_ttl_minutes"),
+    ("OAUTH_ACCESS_TOKEN_TTL_MINUTES", "tokens.oauth_access_token_ttl_minutes"),
+    ("OIDC_LOGIN_TTL_MINUTES", "tokens.oidc_login_ttl_minutes"),
+    ("MAIL_OUTBOX_DIR", "mail.outbox_dir"),
+    ("EMAIL_VERIFY_URL", "email.verify_url"),
+    ("REQUIRE_VERIFIED_EMAIL_TO_POST", "email.require_verified_to_post"),
+    ("LOGIN_THROTTLE_STORE", "auth.login_throttle_store"),
+    ("PASSWORD_HASHER", "password.hasher"),
+    ("ARGON2_MEMORY_KIB", "password.argon2_memory_kib"),
+    ("ARGON2_ITERATIONS", "password.argon2_iterations"),
+    ("ARGON2_PARALLELISM", "password.argon2_parallelism"),
+    ("BCRYPT_COST", "password.bcrypt_cost"),
+    ("PASSWORD_HASHING_CONCURRENCY", "password.hashing_concurrency"),
+    ("PASSWORD_MIN_LENGTH", "password.min_length"),
+    ("OIDC_ISSUER_URL", "oidc.issuer_url"),
+    ("OIDC_CLIENT_ID", "oidc.client_id"),
+    ("OIDC_CLIENT_SECRET", "oidc.client_secret"),
+    ("OIDC_REDIRECT_URL", "oidc.redirect_url"),
+    ("OIDC_AUTO_PROVISION", "oidc.auto_provision"),
+];
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub log: LogConfig,
+    pub tracing: TracingConfig,
+    pub cors: CorsConfig,
+    pub tokens: TokenConfig,
+    pub mail: MailConfig,
+    pub email: EmailConfig,
+    pub auth: AuthConfig,
+    pub password: PasswordConfig,
+    pub oidc: OidcConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // picks the defaults of settings that differ between a laptop and a deployment
+    pub environment: Environment,
+    pub host: String,
+    pub port: u16,
+    // actix starts one worker per core when this is left out
+    pub workers: Option<usize>,
+    // on SIGTERM or SIGINT requests in flight get this long to finish before they are dropped
+    pub shutdown_timeout_seconds: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            environment: Environment::Production,
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            workers: None,
+            shutdown_timeout_seconds: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    Development,
+    Production,
+}
+
+impl FromStr for Environment {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Environment, ()> {
+        match value {
+            "development" => Ok(Environment::Development),
+            "production" => Ok(Environment::Production),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    // how long a query waits for a free connection before it fails
+    pub acquire_timeout_seconds: u64,
+    // 0 keeps idle connections and old connections open forever
+    pub idle_timeout_seconds: u64,
+    pub max_lifetime_seconds: u64,
+    // applied to every connection, 0 lets statements run as long as they like
+    pub statement_timeout_ms: u64,
+    // shows up in pg_stat_activity
+    pub application_name: String,
+    // waiting longer than this for a connection is logged as pool saturation
+    pub slow_acquire_ms: u64,
+    // how often the pool is probed for saturation
+    pub saturation_check_seconds: u64,
+    // apply pending migrations before serving, same as --migrate
+    pub migrate_on_startup: bool,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> DatabaseConfig {
+        DatabaseConfig {
+            url: String::new(),
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout_seconds: 30,
+            idle_timeout_seconds: 10 * 60,
+            max_lifetime_seconds: 30 * 60,
+            statement_timeout_ms: 30_000,
+            application_name: "actixweb-sqlx-sample".to_string(),
+            slow_acquire_ms: 500,
+            saturation_check_seconds: 10,
+            migrate_on_startup: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<LogFormat, ()> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // a tracing filter like info,sqlx=warn, RUST_LOG still wins when it is set
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceExporter {
+    None,
+    Otlp,
+    File,
+}
+
+impl FromStr for TraceExporter {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<TraceExporter, ()> {
+        match value {
+            "none" => Ok(TraceExporter::None),
+            "otlp" => Ok(TraceExporter::Otlp),
+            "file" => Ok(TraceExporter::File),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    // where finished spans are sent, with none they only show up in the logs
+    pub exporter: TraceExporter,
+    // an OTLP/HTTP traces endpoint, e.g. the one of a local collector
+    pub otlp_endpoint: String,
+    // the file exporter writes here
+    pub file: Option<PathBuf>,
+    pub service_name: String,
+}
+
+impl Default for TracingConfig {
+    fn default() -> TracingConfig {
+        TracingConfig {
+            exporter: TraceExporter::None,
+            otlp_endpoint: "http://localhost:4318/v1/traces".to_string(),
+            file: None,
+            service_name: "actixweb-sqlx-sample".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    // scheme://host[:port], the host may start with *. to allow every subdomain; left empty,
+    // development allows the usual local dev servers and production allows no other origin
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    // lets browsers send cookies and read responses to credentialed requests
+    pub allow_credentials: bool,
+    // how long browsers may cache a preflight, by default a minute in development and an
+    // hour in production
+    pub max_age_seconds: Option<usize>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PUT", "DELETE"].map(String::from).to_vec(),
+            allowed_headers: ["Authorization", "Content-Type", "X-Request-Id"]
+                .map(String::from)
+                .to_vec(),
+            allow_credentials: false,
+            max_age_seconds: None,
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn origins(&self, environment: Environment) -> Vec<String> {
+        if !self.allowed_origins.is_empty() {
+            return self.allowed_origins.clone();
+        }
+        match environment {
+            Environment::Development => [
+                "http://localhost:3000",
+                "http://127.0.0.1:3000",
+                "http://localhost:5173",
+                "http://127.0.0.1:5173",
+            ]
+            .map(String::from)
+            .to_vec(),
+            Environment::Production => Vec::new(),
+        }
+    }
+
+    pub fn max_age(&self, environment: Environment) -> usize {
+        match (self.max_age_seconds, environment) {
+            (Some(seconds), _) => seconds,
+            (None, Environment::Development) => 60,
+            (None, Environment::Production) => 60 * 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenConfig {
+    pub reset_code_ttl_minutes: i64,
+    pub email_verification_ttl_hours: i64,
+    pub login_challenge_ttl_minutes: i64,
+    pub oauth_code_ttl_minutes: i64,
+    pub oauth_access_token_ttl_minutes: i64,
+    pub oidc_login_ttl_minutes: i64,
+}
+
+impl Default for TokenConfig {
+    fn default() -> TokenConfig {
+        TokenConfig {
+            reset_code_ttl_minutes: 30,
+            email_verification_ttl_hours: 24,
+            login_challenge_ttl_minutes: 5,
+            oauth_code_ttl_minutes: 10,
+            oauth_access_token_ttl_minutes: 60,
+            oidc_login_ttl_minutes: 10,
+        }
+    }
+}
+
+impl TokenConfig {
+    pub fn reset_code_ttl(&self) -> Duration {
+        Duration::minutes(self.reset_code_ttl_minutes)
+    }
+
+    pub fn email_verification_ttl(&self) -> Duration {
+        Duration::hours(self.email_verification_ttl_hours)
+    }
+
+    pub fn login_challenge_ttl(&self) -> Duration {
+        Duration::minutes(self.login_challenge_ttl_minutes)
+    }
+
+    pub fn oauth_code_ttl(&self) -> Duration {
+        Duration::minutes(self.oauth_code_ttl_minutes)
+    }
+
+    pub fn oauth_access_token_ttl(&self) -> Duration {
+        Duration::minutes(self.oauth_access_token_ttl_minutes)
+    }
+
+    pub fn oidc_login_ttl(&self) -> Duration {
+        Duration::minutes(self.oidc_login_ttl_minutes)
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    // write messages into this directory instead of printing them
+    pub outbox_dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmailConfig {
+    pub verify_url: String,
+    pub require_verified_to_post: bool,
+}
+
+impl Default for EmailConfig {
+    fn default() -> EmailConfig {
+        EmailConfig {
+            verify_url: "http://localhost:8080/verify-email".to_string(),
+            require_verified_to_post: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ThrottleStore {
+    Memory,
+    Postgres,
+}
+
+impl FromStr for ThrottleStore {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<ThrottleStore, ()> {
+        match value {
+            "memory" => Ok(ThrottleStore::Memory),
+            "postgres" => Ok(ThrottleStore::Postgres),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // postgres shares the failed login counters between instances
+    pub login_throttle_store: ThrottleStore,
+}
+
+impl Default for AuthConfig {
+    fn default() -> AuthConfig {
+        AuthConfig {
+            login_throttle_store: ThrottleStore::Memory,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HasherKind {
+    Argon2id,
+    Bcrypt,
+}
+
+impl FromStr for HasherKind {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<HasherKind, ()> {
+        match value {
+            "argon2id" => Ok(HasherKind::Argon2id),
+            "bcrypt" => Ok(HasherKind::Bcrypt),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordConfig {
+    // new hashes use this one, the other is still used to verify hashes it made
+    pub hasher: HasherKind,
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    pub bcrypt_cost: u32,
+    // by default one hash per core, more would only queue up for the cpu anyway
+    pub hashing_concurrency: usize,
+    pub min_length: usize,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> PasswordConfig {
+        PasswordConfig {
+            hasher: HasherKind::Argon2id,
+            argon2_memory_kib: 19 * 1024,
+            argon2_iterations: 2,
+            argon2_parallelism: 1,
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            hashing_concurrency: thread::available_parallelism().map_or(1, |cores| cores.get()),
+            min_length: 8,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    // OIDC login stays off unless an issuer is configured
+    pub issuer_url: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub redirect_url: Option<String>,
+    pub auto_provision: bool,
+}
+
+impl Default for OidcConfig {
+    fn default() -> OidcConfig {
+        OidcConfig {
+            issuer_url: None,
+            client_id: None,
+            client_secret: None,
+            redirect_url: None,
+            auto_provision: true,
+        }
+    }
+}
+
+// everything that was wrong with the configuration, one problem per line
+#[derive(Debug)]
+pub struct ConfigError {
+    pub errors: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for error in &self.errors {
+            writeln!(f, "  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn load(cli: &Cli) -> Result<Config, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+        let mut errors = Vec::new();
+
+        for (name, key) in ENV_VARS {
+            if let Ok(value) = env::var(name) {
+                if let Err(err) = config.set(key, &value) {
+                    errors.push(format!("environment variable {}: {}", name, err));
+                }
+            }
+        }
+
+        for setting in &cli.overrides {
+            let result = match setting.split_once('=') {
+                Some((key, value)) => config.set(key.trim(), value),
+                None => Err("expected KEY=VALUE".to_string()),
+            };
+            if let Err(err) = result {
+                errors.push(format!("--set {}: {}", setting, err));
+            }
+        }
+
+        let flags = [
+            ("--host", "server.host", &cli.host),
+            ("--port", "server.port", &cli.port),
+            ("--database-url", "database.url", &cli.database_url),
+            ("--log-format", "log.format", &cli.log_format),
+        ];
+        for (flag, key, value) in flags {
+            if let Some(value) = value {
+                if let Err(err) = config.set(key, value) {
+                    errors.push(format!("{}: {}", flag, err));
+                }
+            }
+        }
+
+        if cli.migrate {
+            config.database.migrate_on_startup = true;
+        }
+
+        errors.extend(config.validate());
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError { errors })
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let error = |err: String| ConfigError {
+            errors: vec![format!("{}: {}", path.display(), err)],
+        };
+        let contents = fs::read_to_string(path).map_err(|err| error(err.to_string()))?;
+        toml::from_str(&contents).map_err(|err| error(err.to_string()))
+    }
+
+    // the value comes from an environment variable or a flag, so it is always text
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "server.environment" => {
+                self.server.environment = parse(value, "development or production")?
+            }
+            "server.host" => self.server.host = value.to_string(),
+            "server.port" => self.server.port = parse(value, "a port number")?,
+            "server.workers" => self.server.workers = Some(parse(value, "a number of workers")?),
+            "server.shutdown_timeout_seconds" => {
+                self.server.shutdown_timeout_seconds = parse(value, "a number of seconds")?
+            }
+            "database.url" => self.database.url = value.to_string(),
+            "database.max_connections" => {
+                self.database.max_connections = parse(value, "a number of connections")?
+            }
+            "database.min_connections" => {
+                self.database.min_connections = parse(value, "a number of connections")?
+            }
+            "database.acquire_timeout_seconds" => {
+                self.database.acquire_timeout_seconds = parse(value, "a number of seconds")?
+            }
+            "database.idle_timeout_seconds" => {
+                self.database.idle_timeout_seconds = parse(value, "a number of seconds")?
+            }
+            "database.max_lifetime_seconds" => {
+                self.database.max_lifetime_seconds = parse(value, "a number of seconds")?
+            }
+            "database.statement_timeout_ms" => {
+                self.database.statement_timeout_ms = parse(value, "a number of milliseconds")?
+            }
+            "database.application_name" => self.database.application_name = value.to_string(),
+            "database.slow_acquire_ms" => {
+                self.database.slow_acquire_ms = parse(value, "a number of milliseconds")?
+            }
+            "database.saturation_check_seconds" => {
+                self.database.saturation_check_seconds = parse(value, "a number of seconds")?
+            }
+            "database.migrate_on_startup" => {
+                self.database.migrate_on_startup = parse(value, "true or false")?
+            }
+            "log.level" => self.log.level = value.to_string(),
+            "log.format" => self.log.format = parse(value, "text or json")?,
+            "tracing.exporter" => self.tracing.exporter = parse(value, "none, otlp or file")?,
+            "tracing.otlp_endpoint" => self.tracing.otlp_endpoint = value.to_string(),
+            "tracing.file" => self.tracing.file = Some(PathBuf::from(value)),
+            "tracing.service_name" => self.tracing.service_name = value.to_string(),
+            "cors.allowed_origins" => self.cors.allowed_origins = parse_list(value),
+            "cors.allowed_methods" => self.cors.allowed_methods = parse_list(value),
+            "cors.allowed_headers" => self.cors.allowed_headers = parse_list(value),
+            "cors.allow_credentials" => {
+                self.cors.allow_credentials = parse(value, "true or false")?
+            }
+            "cors.max_age_seconds" => {
+                self.cors.max_age_seconds = Some(parse(value, "a number of seconds")?)
+            }
+            "tokens.reset_code_ttl_minutes" => {
+                self.tokens.reset_code_ttl_minutes = parse(value, "a number of minutes")?
+            }
+            "tokens.email_verification_ttl_hours" => {
+                self.tokens.email_verification_ttl_hours = parse(value, "a number of hours")?
+            }
+            "tokens.login_challenge_ttl_minutes" => {
+                self.tokens.login_challenge_ttl_minutes = parse(value, "a number of minutes")?
+            }
+            "tokens.oauth_code_ttl_minutes" => {
+                self.tokens.oauth_code_ttl_minutes = parse(value, "a number of minutes")?
+            }
+            "tokens.oauth_access_token_ttl_minutes" => {
+                self.tokens.oauth_access_token_ttl_minutes = parse(value, "a number of minutes")?
+            }
+            "tokens.oidc_login_ttl_minutes" => {
+                self.tokens.oidc_login_ttl_minutes = parse(value, "a number of minutes")?
+            }
+            "mail.outbox_dir" => self.mail.outbox_dir = Some(PathBuf::from(value)),
+            "email.verify_url" => self.email.verify_url = value.to_string(),
+            "email.require_verified_to_post" => {
+                self.email.require_verified_to_post = parse(value, "true or false")?
+            }
+            "auth.login_throttle_store" => {
+                self.auth.login_throttle_store = parse(value, "memory or postgres")?
+            }
+            "password.hasher" => self.password.hasher = parse(value, "argon2id or bcrypt")?,
+            "password.argon2_memory_kib" => {
+                self.password.argon2_memory_kib = parse(value, "a number of KiB")?
+            }
+            "password.argon2_iterations" => {
+                self.password.argon2_iterations = parse(value, "a number of iterations")?
+            }
+            "password.argon2_parallelism" => {
+                self.password.argon2_parallelism = parse(value, "a number of lanes")?
+            }
+            "password.bcrypt_cost" => self.password.bcrypt_cost = parse(value, "a cost factor")?,
+            "password.hashing_concurrency" => {
+                self.password.hashing_concurrency = parse(value, "a number of hashes")?
+            }
+            "password.min_length" => {
+                self.password.min_length = parse(value, "a number of characters")?
+            }
+            "oidc.issuer_url" => self.oidc.issuer_url = Some(value.to_string()),
+            "oidc.client_id" => self.oidc.client_id = Some(value.to_string()),
+            "oidc.client_secret" => self.oidc.client_secret = Some(value.to_string()),
+            "oidc.redirect_url" => self.oidc.redirect_url = Some(value.to_string()),
+            "oidc.auto_provision" => self.oidc.auto_provision = parse(value, "true or false")?,
+            _ => return Err(format!("unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.server.host.is_empty() {
+            errors.push("server.host must not be empty".to_string());
+        }
+        if self.server.workers == Some(0) {
+            errors.push("server.workers must be at least 1".to_string());
+        }
+
+        if self.database.url.is_empty() {
+            errors.push(
+                "database.url is not set, use DATABASE_URL or the [database] section".to_string(),
+            );
+        } else if !self.database.url.starts_with("postgres://")
+            && !self.database.url.starts_with("postgresql://")
+        {
+            errors.push("database.url must be a postgres:// URL".to_string());
+        }
+        if self.database.max_connections == 0 {
+            errors.push("database.max_connections must be at least 1".to_string());
+        }
+        if self.database.min_connections > self.database.max_connections {
+            errors.push(format!(
+                "database.min_connections ({}) is more than database.max_connections ({})",
+                self.database.min_connections, self.database.max_connections
+            ));
+        }
+
+        if self.database.acquire_timeout_seconds == 0 {
+            errors.push("database.acquire_timeout_seconds must be at least 1".to_string());
+        }
+        if self.database.saturation_check_seconds == 0 {
+            errors.push("database.saturation_check_seconds must be at least 1".to_string());
+        }
+        // postgres truncates longer names silently
+        if self.database.application_name.len() > 63 {
+            errors.push("database.application_name must be at most 63 bytes".to_string());
+        }
+
+        if self.tracing.exporter == TraceExporter::Otlp
+            && !self.tracing.otlp_endpoint.starts_with("http://")
+            && !self.tracing.otlp_endpoint.starts_with("https://")
+        {
+            errors.push("tracing.otlp_endpoint must be an http(s) URL".to_string());
+        }
+        if self.tracing.exporter == TraceExporter::File && self.tracing.file.is_none() {
+            errors.push("tracing.file is required when tracing.exporter is file".to_string());
+        }
+        if self.tracing.service_name.is_empty() {
+            errors.push("tracing.service_name must not be empty".to_string());
+        }
+
+        for origin in &self.cors.allowed_origins {
+            if !is_valid_origin(origin) {
+                errors.push(format!(
+                    "cors.allowed_origins: {:?} is not an origin like https://example.com",
+                    origin
+                ));
+            }
+        }
+        // actix-cors panics on anything it can't parse, so this is checked up front
+        for method in &self.cors.allowed_methods {
+            if method.is_empty() || !method.bytes().all(|byte| byte.is_ascii_uppercase()) {
+                errors.push(format!(
+                    "cors.allowed_methods: {:?} is not a method like GET",
+                    method
+                ));
+            }
+        }
+        for header in &self.cors.allowed_headers {
+            let is_token = |byte: u8| byte.is_ascii_alphanumeric() || byte == b'-';
+            if header.is_empty() || !header.bytes().all(is_token) {
+                errors.push(format!(
+                    "cors.allowed_headers: {:?} is not a header name like Authorization",
+                    header
+                ));
+            }
+        }
+
+        let ttls = [
+            ("reset_code_ttl_minutes", self.tokens.reset_code_ttl_minutes),
+            (
+                "email_verification_ttl_hours",
+                self.tokens.email_verification_ttl_hours,
+            ),
+            (
+                "login_challenge_ttl_minutes",
+                self.tokens.login_challenge_ttl_minutes,
+            ),
+            ("oauth_code_ttl_minutes", self.tokens.oauth_code_ttl_minutes),
+            (
+                "oauth_access_token_ttl_minutes",
+                self.tokens.oauth_access_token_ttl_minutes,
+            ),
+            ("oidc_login_ttl_minutes", self.tokens.oidc_login_ttl_minutes),
+        ];
+        for (name, ttl) in ttls {
+            if ttl <= 0 {
+                errors.push(format!("tokens.{} must be positive", name));
+            }
+        }
+
+        if !self.email.verify_url.starts_with("http://")
+            && !self.email.verify_url.starts_with("https://")
+        {
+            errors.push("email.verify_url must be an http(s) URL".to_string());
+        }
+
+        if self.password.hashing_concurrency == 0 {
+            errors.push("password.hashing_concurrency must be at least 1".to_string());
+        }
+        if self.password.min_length == 0 {
+            errors.push("password.min_length must be at least 1".to_string());
+        }
+
+        if self.oidc.issuer_url.is_some() {
+            if self.oidc.client_id.is_none() {
+                errors.push("oidc.client_id is required when oidc.issuer_url is set".to_string());
+            }
+            if self.oidc.redirect_url.is_none() {
+                errors
+                    .push("oidc.redirect_url is required when oidc.issuer_url is set".to_string());
+            }
+        }
+
+        errors
+    }
+}
+
+fn parse<T: FromStr>(value: &str, expected: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("expected {}, got {:?}", expected, value))
+}
+
+// comma separated, as environment variables and --set give lists
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn is_valid_origin(origin: &str) -> bool {
+    let host = match origin
+        .strip_prefix("https://")
+        .or_else(|| origin.strip_prefix("http://"))
+    {
+        Some(host) => host,
+        None => return false,
+    };
+    let host = host.strip_prefix("*.").unwrap_or(host);
+    !host.is_empty() && !host.contains(['/', '*', '?', '#'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use uuid::Uuid;
+
+    // a throwaway config file, removed again when dropped
+    struct TomlFile(PathBuf);
+
+    impl TomlFile {
+        fn new(contents: &str) -> TomlFile {
+            let path = env::temp_dir().join(format!("config-{}.toml", Uuid::new_v4()));
+            fs::write(&path, contents).unwrap();
+            TomlFile(path)
+        }
+    }
+
+    impl Drop for TomlFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn load(file: &TomlFile, args: &[&str]) -> Result<Config, ConfigError> {
+        let path = file.0.to_str().unwrap();
+        let cli = Cli::parse_from(["app", "--config", path].iter().chain(args));
+        Config::load(&cli)
+    }
+
+    #[test]
+    fn each_layer_overrides_the_one_before() {
+        let defaults = TomlFile::new("[database]\nurl = \"postgres://localhost/app\"\n");
+        let file = TomlFile::new(
+            "[server]\nworkers = 2\n[database]\nurl = \"postgres://localhost/app\"\n",
+        );
+        let workers = |args: &[&str]| load(&file, args).map(|config| config.server.workers);
+
+        // WORKERS is not in .env, so no other test sets it while this one runs
+        env::remove_var("WORKERS");
+        assert_eq!(load(&defaults, &[]).unwrap().server.workers, None);
+        assert_eq!(workers(&[]).unwrap(), Some(2));
+
+        env::set_var("WORKERS", "3");
+        let from_env = workers(&[]);
+        let from_set = workers(&["--set", "server.workers=4"]);
+        env::remove_var("WORKERS");
+        assert_eq!(from_env.unwrap(), Some(3));
+        assert_eq!(from_set.unwrap(), Some(4));
+
+        // there is no --workers flag, the port has both and --set beats PORT either way
+        let from_flag = load(&file, &["--set", "server.port=1003", "--port", "1004"]);
+        assert_eq!(from_flag.unwrap().server.port, 1004);
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        // DATABASE_URL may be set and WORKERS is set by the test above, so neither is used here
+        let file = TomlFile::new(
+            "[database]\nurl = \"postgres://localhost/app\"\n\
+             max_connections = 0\nacquire_timeout_seconds = 0\n",
+        );
+        let args = ["--set", "server.port=eighty", "--set", "nope", "--host", ""];
+
+        let errors = match load(&file, &args) {
+            Ok(_) => panic!("the configuration should have been rejected"),
+            Err(err) => err.errors,
+        };
+        assert_eq!(
+            errors,
+            vec![
+                "--set server.port=eighty: expected a port number, got \"eighty\"",
+                "--set nope: expected KEY=VALUE",
+                "server.host must not be empty",
+                "database.max_connections must be at least 1",
+                "database.acquire_timeout_seconds must be at least 1",
+            ]
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use crate::config::{CorsConfig, Environment};
+use crate::request_id::REQUEST_ID_HEADER;
+use actix_cors::Cors;
+use actix_web::dev::RequestHead;
+use actix_web::http::HeaderValue;
+
+// built for every worker; the patterns were checked by Config::validate, which actix-cors
+// relies on, as it panics on origins, methods and headers it can't parse
+pub fn from_config(config: &CorsConfig, environment: Environment) -> Cors {
+    let (wildcards, exact): (Vec<String>, Vec<String>) = config
+        .origins(environment)
+        .into_iter()
+        .partition(|origin| origin.contains("://*."));
+
+    let mut cors = Cors::default()
+        .allowed_methods(config.allowed_methods.iter().map(String::as_str))
+        .allowed_headers(config.allowed_headers.iter().map(String::as_str))
+        .expose_headers(vec![REQUEST_ID_HEADER])
+        .max_age(config.max_age(environment));
+    for origin in &exact {
+        cors = cors.allowed_origin(origin);
+    }
+    if !wildcards.is_empty() {
+        cors = cors.allowed_origin_fn(move |origin: &HeaderValue, _: &RequestHead| {
+            let origin = match origin.to_str() {
+                Ok(origin) => origin,
+                Err(_) => return false,
+            };
+            wildcards
+                .iter()
+                .any(|pattern| matches_wildcard(pattern, origin))
+        });
+    }
+    if config.allow_credentials {
+        cors = cors.supports_credentials();
+    }
+    cors
+}
+
+// https://*.example.com matches https://app.example.com and https://a.b.example.com, but
+// neither https://example.com itself nor a different scheme or port
+fn matches_wildcard(pattern: &str, origin: &str) -> bool {
+    let (prefix, suffix) = match pattern.split_once("*.") {
+        Some((prefix, domain)) => (prefix, format!(".{}", domain)),
+        None => return false,
+    };
+    let subdomain = match origin
+        .strip_prefix(prefix)
+        .and_then(|rest| rest.strip_suffix(suffix.as_str()))
+    {
+        Some(subdomain) => subdomain,
+        None => return false,
+    };
+    !subdomain.is_empty()
+        && subdomain
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATTERN: &str = "https://*.example.com";
+
+    #[test]
+    fn subdomains_match_at_any_depth() {
+        assert!(matches_wildcard(PATTERN, "https://app.example.com"));
+        assert!(matches_wildcard(PATTERN, "https://a.b.example.com"));
+    }
+
+    #[test]
+    fn the_bare_domain_is_rejected() {
+        assert!(!matches_wildcard(PATTERN, "https://example.com"));
+        assert!(!matches_wildcard(PATTERN, "https://.example.com"));
+    }
+
+    #[test]
+    fn scheme_and_port_must_match() {
+        assert!(!matches_wildcard(PATTERN, "http://app.example.com"));
+        assert!(!matches_wildcard(PATTERN, "https://app.example.com:8443"));
+        assert!(matches_wildcard(
+            "https://*.example.com:8443",
+            "https://app.example.com:8443"
+        ));
+        assert!(!matches_wildcard(
+            "https://*.example.com:8443",
+            "https://app.example.com"
+        ));
+    }
+
+    #[test]
+    fn lookalikes_are_rejected() {
+        assert!(!matches_wildcard(PATTERN, "https://evilexample.com"));
+        assert!(!matches_wildcard(
+            PATTERN,
+            "https://app.example.com.evil.com"
+        ));
+        assert!(!matches_wildcard(PATTERN, "https://evil.com/.example.com"));
+        assert!(!matches_wildcard(PATTERN, "https://evil.com?.example.com"));
+    }
+}
@@ -0,0 +1,229 @@
+// Runs the migrations embedded by build.rs. Bookkeeping goes into the same _sqlx_migrations
+// table sqlx-cli uses, with the same checksums, so databases migrated either way stay
+// interchangeable.
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::info;
+use sha2::{Digest, Sha384};
+use sqlx::{Executor, PgPool};
+use std::collections::HashMap;
+use std::time::Instant;
+
+// held for the whole run, so instances starting together apply each migration only once
+const LOCK_ID: i64 = 0x6163_7469_7877_6562;
+
+pub struct EmbeddedMigration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+const MIGRATIONS: &[EmbeddedMigration] = include!(concat!(env!("OUT_DIR"), "/migrations.rs"));
+
+impl EmbeddedMigration {
+    fn checksum(&self) -> Vec<u8> {
+        Sha384::digest(self.sql.as_bytes()).to_vec()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    // applied, but the embedded file has been edited since
+    Modified,
+    // a previous run failed half way, only sqlx-cli leaves these behind
+    Failed,
+    // applied to the database but unknown to this binary, e.g. one built from an older tree
+    Unknown,
+}
+
+impl MigrationState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MigrationState::Applied => "applied",
+            MigrationState::Pending => "pending",
+            MigrationState::Modified => "modified",
+            MigrationState::Failed => "failed",
+            MigrationState::Unknown => "unknown",
+        }
+    }
+}
+
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+    pub installed_on: Option<DateTime<Utc>>,
+}
+
+#[derive(sqlx::FromRow)]
+struct AppliedMigration {
+    version: i64,
+    description: String,
+    checksum: Vec<u8>,
+    success: bool,
+    installed_on: DateTime<Utc>,
+}
+
+pub async fn status(pool: &PgPool) -> Result<Vec<MigrationStatus>> {
+    let mut applied = applied_migrations(pool).await?;
+    let mut statuses = MIGRATIONS
+        .iter()
+        .map(|migration| {
+            let (state, installed_on) = match applied.remove(&migration.version) {
+                Some(applied) if !applied.success => {
+                    (MigrationState::Failed, Some(applied.installed_on))
+                }
+                Some(applied) if applied.checksum != migration.checksum() => {
+                    (MigrationState::Modified, Some(applied.installed_on))
+                }
+                Some(applied) => (MigrationState::Applied, Some(applied.installed_on)),
+                None => (MigrationState::Pending, None),
+            };
+            MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                state,
+                installed_on,
+            }
+        })
+        .collect::<Vec<MigrationStatus>>();
+
+    statuses.extend(
+        applied
+            .into_iter()
+            .map(|(version, applied)| MigrationStatus {
+                version,
+                description: applied.description,
+                state: MigrationState::Unknown,
+                installed_on: Some(applied.installed_on),
+            }),
+    );
+    statuses.sort_by_key(|status| status.version);
+
+    Ok(statuses)
+}
+
+// applies every pending migration in order, each in its own transaction
+pub async fn run(pool: &PgPool) -> Result<usize> {
+    let mut conn = pool.acquire().await?;
+    // session level, so the lock has to be taken and released on this one connection
+    sqlx::query("SELECT pg_advisory_lock($1)")
+        .bind(LOCK_ID)
+        .execute(&mut *conn)
+        .await?;
+
+    let result = apply_pending(pool).await;
+
+    sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(LOCK_ID)
+        .execute(&mut *conn)
+        .await?;
+
+    result
+}
+
+async fn apply_pending(pool: &PgPool) -> Result<usize> {
+    create_migrations_table(pool).await?;
+
+    // checked under the lock, another instance may have just finished some of them
+    let statuses = status(pool).await?;
+    if let Some(status) = statuses.iter().find(|status| {
+        !matches!(
+            status.state,
+            MigrationState::Applied | MigrationState::Pending
+        )
+    }) {
+        return Err(anyhow!(
+            "migration {} ({}) is {}, fix the database before migrating",
+            status.version,
+            status.description,
+            status.state.as_str()
+        ));
+    }
+
+    let mut n_applied = 0;
+    for migration in MIGRATIONS {
+        let pending = statuses.iter().any(|status| {
+            status.version == migration.version && status.state == MigrationState::Pending
+        });
+        if !pending {
+            continue;
+        }
+
+        info!(
+            "applying migration {} ({})",
+            migration.version, migration.description
+        );
+        let started = Instant::now();
+        let mut tx = pool.begin().await?;
+        // a plain string runs as a simple query, so one file may hold several statements
+        (&mut tx).execute(migration.sql).await.map_err(|err| {
+            anyhow!(
+                "migration {} ({}) failed: {}",
+                migration.version,
+                migration.description,
+                err
+            )
+        })?;
+        sqlx::query(
+            r#"
+            INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+            VALUES ($1, $2, TRUE, $3, $4)
+            "#,
+        )
+        .bind(migration.version)
+        .bind(migration.description)
+        .bind(migration.checksum())
+        .bind(started.elapsed().as_nanos() as i64)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        n_applied += 1;
+    }
+
+    Ok(n_applied)
+}
+
+// query instead of query!, the table doesn't exist until the first run creates it
+async fn create_migrations_table(pool: &PgPool) -> Result<()> {
+    pool.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS _sqlx_migrations (
+            version BIGINT PRIMARY KEY,
+            description TEXT NOT NULL,
+            installed_on TIMESTAMPTZ NOT NULL DEFAULT now(),
+            success BOOLEAN NOT NULL,
+            checksum BYTEA NOT NULL,
+            execution_time BIGINT NOT NULL
+        )
+        "#,
+    )
+    .await?;
+    Ok(())
+}
+
+async fn applied_migrations(pool: &PgPool) -> Result<HashMap<i64, AppliedMigration>> {
+    // status doesn't create the table, a database nothing was applied to just has none
+    let (exists,): (bool,) = sqlx::query_as("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+        .fetch_one(pool)
+        .await?;
+    if !exists {
+        return Ok(HashMap::new());
+    }
+
+    let rows: Vec<AppliedMigration> = sqlx::query_as(
+        r#"
+        SELECT version, description, checksum, success, installed_on
+        FROM _sqlx_migrations
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|applied| (applied.version, applied))
+        .collect())
+}
@@ -0,0 +1,111 @@
+use crate::config::DatabaseConfig;
+use crate::metrics::Metrics;
+use actix_web::rt;
+use actix_web::rt::time::interval;
+use anyhow::Result;
+use futures::future::{self, Either};
+use log::{error, warn};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+pub mod migrate;
+
+pub async fn connect(config: &DatabaseConfig) -> Result<PgPool> {
+    let statement_timeout = format!("{}ms", config.statement_timeout_ms);
+    let application_name = config.application_name.clone();
+
+    let pool = PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .connect_timeout(Duration::from_secs(config.acquire_timeout_seconds))
+        .idle_timeout(seconds_or_none(config.idle_timeout_seconds))
+        .max_lifetime(seconds_or_none(config.max_lifetime_seconds))
+        // set_config instead of SET, which can't take bind parameters
+        .after_connect(move |conn| {
+            let statement_timeout = statement_timeout.clone();
+            let application_name = application_name.clone();
+            Box::pin(async move {
+                sqlx::query!(
+                    r#"
+                    SELECT set_config('statement_timeout', $1, false) AS statement_timeout,
+                        set_config('application_name', $2, false) AS application_name
+                    "#,
+                    statement_timeout,
+                    application_name,
+                )
+                .fetch_one(conn)
+                .await?;
+                Ok(())
+            })
+        })
+        .connect(&config.url)
+        .await?;
+
+    Ok(pool)
+}
+
+// sqlx acquires connections inside every query, so instead of timing those the pool is
+// probed on an interval; a slow probe means requests are queueing for connections too.
+// It runs until the returned sender is used or dropped.
+pub fn spawn_saturation_monitor(
+    pool: PgPool,
+    config: &DatabaseConfig,
+    metrics: Metrics,
+) -> oneshot::Sender<()> {
+    let threshold = Duration::from_millis(config.slow_acquire_ms);
+    let max_connections = config.max_connections;
+    let period = Duration::from_secs(config.saturation_check_seconds);
+
+    let (stop, mut stopped) = oneshot::channel::<()>();
+    rt::spawn(async move {
+        let mut ticks = interval(period);
+        loop {
+            if let Either::Right(_) = future::select(Box::pin(ticks.tick()), &mut stopped).await {
+                break;
+            }
+
+            let started = Instant::now();
+            let result = pool.acquire().await;
+            let waited = started.elapsed();
+            metrics
+                .db_pool_acquire_duration
+                .observe(waited.as_secs_f64());
+            match result {
+                Ok(conn) => drop(conn),
+                // closed while shutting down, before the stop got here
+                Err(_) if pool.is_closed() => break,
+                Err(err) => {
+                    error!(
+                        "database pool exhausted: no connection after {}ms ({} of {} open): {}",
+                        waited.as_millis(),
+                        pool.size(),
+                        max_connections,
+                        err
+                    );
+                    continue;
+                }
+            }
+
+            if waited > threshold {
+                warn!(
+                    "database pool saturated: {}ms wait for a connection ({} of {} open, {} idle)",
+                    waited.as_millis(),
+                    pool.size(),
+                    max_connections,
+                    pool.num_idle()
+                );
+            }
+        }
+    });
+    stop
+}
+
+fn seconds_or_none(seconds: u64) -> Option<Duration> {
+    if seconds == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(seconds))
+    }
+}
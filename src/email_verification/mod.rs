@@ -0,0 +1,112 @@
+use crate::config::{EmailConfig, TokenConfig};
+use crate::token::{generate_random_u8s, hash_secret};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub struct EmailSettings {
+    // page the verification link points at, the code is appended as a query parameter
+    pub verify_url: String,
+    pub require_verified_to_post: bool,
+    // how long a verification link stays valid after it has been sent
+    pub verification_ttl: Duration,
+}
+
+impl EmailSettings {
+    pub fn from_config(config: &EmailConfig, tokens: &TokenConfig) -> EmailSettings {
+        EmailSettings {
+            verify_url: config.verify_url.clone(),
+            require_verified_to_post: config.require_verified_to_post,
+            verification_ttl: tokens.email_verification_ttl(),
+        }
+    }
+
+    pub fn verify_link(&self, code: &str) -> String {
+        // the configured page may already carry a query string of its own
+        let separator = if self.verify_url.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        format!("{}{}code={}", self.verify_url, separator, code)
+    }
+}
+
+#[derive(Serialize)]
+pub struct EmailVerification {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub email: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl EmailVerification {
+    // only the hash is stored, so the plain code is handed back to the caller to be mailed
+    pub async fn create(
+        user_id: Uuid,
+        email: &str,
+        ttl: Duration,
+        pool: &PgPool,
+    ) -> Result<(EmailVerification, String)> {
+        let code = base64::encode_config(generate_random_u8s(24), base64::URL_SAFE_NO_PAD);
+        let verification = EmailVerification {
+            id: Uuid::new_v4(),
+            user_id,
+            email: email.to_string(),
+            expires_at: Utc::now() + ttl,
+        };
+
+        let mut tx = pool.begin().await?;
+
+        // only the latest link is valid
+        sqlx::query!(
+            r#"
+            DELETE FROM email_verifications
+            WHERE user_id = $1
+            "#,
+            user_id,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO email_verifications (id, code_hash, user_id, email, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            verification.id,
+            hash_secret(&code),
+            verification.user_id,
+            verification.email,
+            verification.expires_at,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok((verification, code))
+    }
+
+    // consumes the code and returns the user and address it was issued for
+    pub async fn redeem(code: &str, pool: &PgPool) -> Result<Option<(Uuid, String)>> {
+        let mut tx = pool.begin().await?;
+
+        let rec = sqlx::query!(
+            r#"
+            DELETE FROM email_verifications
+            WHERE code_hash = $1 AND expires_at > now()
+            RETURNING user_id, email
+            "#,
+            hash_secret(code),
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(rec.map(|rec| (rec.user_id, rec.email)))
+    }
+}
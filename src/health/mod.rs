@@ -0,0 +1,23 @@
+mod routes;
+
+pub use routes::init;
+
+use chrono::{DateTime, Utc};
+use std::time::Instant;
+
+// created once in main before the workers start, so every worker reports the same uptime
+pub struct ServerInfo {
+    pub started_at: DateTime<Utc>,
+    pub started: Instant,
+    pub max_connections: u32,
+}
+
+impl ServerInfo {
+    pub fn new(max_connections: u32) -> ServerInfo {
+        ServerInfo {
+            started_at: Utc::now(),
+            started: Instant::now(),
+            max_connections,
+        }
+    }
+}
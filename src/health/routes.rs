@@ -0,0 +1,89 @@
+use crate::db::migrate::{self, MigrationState};
+use crate::health::ServerInfo;
+use actix_web::rt::time::timeout;
+use actix_web::{get, web, HttpResponse, Responder};
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde_json::json;
+use sqlx::PgPool;
+use std::time::{Duration, Instant};
+
+// well below the few seconds orchestrators usually give a probe
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz).service(readyz).service(status);
+}
+
+// liveness only, answering at all means the process isn't wedged
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+#[get("/readyz")]
+async fn readyz(db_pool: web::Data<PgPool>) -> impl Responder {
+    let result = match timeout(CHECK_TIMEOUT, check_ready(db_pool.get_ref())).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!(
+            "database didn't answer within {}s",
+            CHECK_TIMEOUT.as_secs()
+        )),
+    };
+    match result {
+        Ok(()) => HttpResponse::Ok().json(json!({ "status": "ready" })),
+        Err(err) => {
+            warn!("not ready: {}", err);
+            HttpResponse::ServiceUnavailable().json(json!({
+                "status": "unavailable",
+                "reason": err.to_string(),
+            }))
+        }
+    }
+}
+
+#[get("/status")]
+async fn status(info: web::Data<ServerInfo>, db_pool: web::Data<PgPool>) -> impl Responder {
+    let pool = db_pool.get_ref();
+
+    // how long a request would queue for a connection right now
+    let started = Instant::now();
+    let acquire_ms = match timeout(CHECK_TIMEOUT, pool.acquire()).await {
+        Ok(Ok(conn)) => {
+            drop(conn);
+            Some(started.elapsed().as_millis() as u64)
+        }
+        _ => None,
+    };
+
+    HttpResponse::Ok().json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": env!("GIT_COMMIT"),
+        "started_at": info.started_at,
+        "uptime_seconds": info.started.elapsed().as_secs(),
+        "database": {
+            "connections": pool.size(),
+            "idle": pool.num_idle(),
+            "max_connections": info.max_connections,
+            "acquire_ms": acquire_ms,
+        },
+    }))
+}
+
+async fn check_ready(pool: &PgPool) -> Result<()> {
+    sqlx::query!("SELECT 1 AS one").fetch_one(pool).await?;
+
+    // migrations only known to the database are fine, a newer instance may already be rolling out
+    let behind = migrate::status(pool)
+        .await?
+        .into_iter()
+        .filter(|migration| migration.state != MigrationState::Applied)
+        .filter(|migration| migration.state != MigrationState::Unknown)
+        .map(|migration| format!("{} {}", migration.version, migration.state.as_str()))
+        .collect::<Vec<String>>();
+    if !behind.is_empty() {
+        return Err(anyhow!("migrations not up to date: {}", behind.join(", ")));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use crate::config::MailConfig;
+use anyhow::Result;
+use chrono::Utc;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub struct Message {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Message {
+    fn render(&self) -> String {
+        format!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            self.to, self.subject, self.body
+        )
+    }
+}
+
+// anything that can deliver a message; handlers only ever see this trait
+pub trait Mailer: Send + Sync {
+    fn send(&self, message: &Message) -> Result<()>;
+}
+
+// prints every message, handy when running the server locally
+pub struct StdoutMailer;
+
+impl Mailer for StdoutMailer {
+    fn send(&self, message: &Message) -> Result<()> {
+        println!("{}", message.render());
+        Ok(())
+    }
+}
+
+// drops every message as a file into an outbox directory
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<FileMailer> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileMailer { dir })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, message: &Message) -> Result<()> {
+        let file_name = format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S%.f"),
+            Uuid::new_v4()
+        );
+        fs::write(self.dir.join(file_name), message.render())?;
+        Ok(())
+    }
+}
+
+// an outbox directory selects the file outbox, otherwise messages go to stdout
+pub fn from_config(config: &MailConfig) -> Result<Arc<dyn Mailer>> {
+    match &config.outbox_dir {
+        Some(dir) => Ok(Arc::new(FileMailer::new(dir)?)),
+        None => Ok(Arc::new(StdoutMailer)),
+    }
+}
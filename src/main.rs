@@ -1,13 +1,41 @@
-use actix_web::{middleware, web, App, HttpResponse, HttpServer, Responder};
+use actix_web::dev::Server;
+use actix_web::middleware::Condition;
+use actix_web::{web, App, HttpResponse, HttpServer, Responder};
 use anyhow::Result;
+use clap::Parser;
+use cli::{Cli, Command};
+use config::Config;
 use dotenv::dotenv;
 use log::info;
 use sqlx::PgPool;
-use std::env;
+use std::process;
+use std::time::Instant;
+use tokio::sync::oneshot;
 
+mod admin;
+mod api_key;
+mod audit;
 mod auth;
+mod cli;
+mod config;
+mod cors;
+mod db;
+mod email_verification;
+mod health;
+mod mail;
+mod metrics;
+mod oauth;
+mod oidc;
+mod password;
 mod post;
+mod relation;
+mod request_id;
+mod reset_code;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
 mod token;
+mod two_factor;
 mod user;
 
 async fn hello() -> impl Responder {
@@ -17,30 +45,198 @@ async fn hello() -> impl Responder {
 #[actix_web::main]
 async fn main() -> Result<()> {
     dotenv().ok();
-    env_logger::init();
+    let cli = Cli::parse();
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL is not set in .env file");
-    let host = env::var("HOST").expect("HOST is not set in .env file");
-    let port = env::var("PORT")
-        .expect("PORT is not set in .env file")
-        .parse::<u16>()
-        .expect("PORT should be a u16");
+    // logging isn't set up before the configuration is known, so problems go to stderr
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(err) => {
+            eprint!("{}", err);
+            process::exit(2);
+        }
+    };
+    if let Err(err) = telemetry::init(&config.log, &config.tracing) {
+        eprintln!("error setting up logging: {}", err);
+        process::exit(2);
+    }
 
-    info!("using postgresql database at: {}", &database_url);
-    let db_pool = PgPool::connect(&database_url).await?;
+    info!("using postgresql database at: {}", &config.database.url);
+    let db_pool = db::connect(&config.database).await?;
+
+    let result = match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config, db_pool).await,
+        Command::User(command) => {
+            let passwords = password::Passwords::from_config(&config.password)?;
+            cli::commands::user(command, &passwords, &db_pool).await
+        }
+        Command::Token(command) => cli::commands::token(command, &db_pool).await,
+        Command::Db(command) => cli::commands::db(command, &db_pool).await,
+    };
+    telemetry::shutdown();
+    result
+}
+
+async fn serve(config: Config, db_pool: PgPool) -> Result<()> {
+    if config.database.migrate_on_startup {
+        let n_applied = db::migrate::run(&db_pool).await?;
+        info!("applied {} migration(s)", n_applied);
+    }
+
+    let metrics = metrics::Metrics::new(config.database.max_connections)?;
+    let stop_monitor =
+        db::spawn_saturation_monitor(db_pool.clone(), &config.database, metrics.clone());
+    let mailer = mail::from_config(&config.mail)?;
+    let email_settings = web::Data::new(email_verification::EmailSettings::from_config(
+        &config.email,
+        &config.tokens,
+    ));
+    let login_throttle = web::Data::new(auth::LoginThrottle::new(
+        config.auth.login_throttle_store,
+        &db_pool,
+    ));
+    let passwords = web::Data::new(password::Passwords::from_config(&config.password)?);
+    let oidc = web::Data::new(oidc::OidcProvider::from_config(&config.oidc));
+    let tokens = web::Data::new(config.tokens.clone());
+    let server_info = web::Data::new(health::ServerInfo::new(config.database.max_connections));
+    let metrics = web::Data::new(metrics);
+    let cors_config = config.cors.clone();
+    let environment = config.server.environment;
+    // without any allowed origin the middleware is left out, it would refuse every request
+    // that sends an Origin header
+    let cors_enabled = !cors_config.origins(environment).is_empty();
+    // what the shutdown needs once the originals have moved into the workers' factory
+    let started = server_info.started;
+    let shutdown_pool = db_pool.clone();
+    let shutdown_metrics = metrics.get_ref().clone();
 
     let server = HttpServer::new(move || {
         App::new()
             .data(db_pool.clone())
-            .wrap(middleware::Logger::default())
+            .data(mailer.clone())
+            .app_data(email_settings.clone())
+            .app_data(login_throttle.clone())
+            .app_data(passwords.clone())
+            .app_data(oidc.clone())
+            .app_data(tokens.clone())
+            .app_data(server_info.clone())
+            .app_data(metrics.clone())
+            .wrap(telemetry::RequestTracing)
+            .wrap(metrics::RequestMetrics::new(metrics.get_ref().clone()))
+            .wrap(Condition::new(
+                cors_enabled,
+                cors::from_config(&cors_config, environment),
+            ))
+            .wrap(request_id::RequestIdentifier)
             .route("/", web::get().to(hello))
+            .configure(health::init)
+            .configure(metrics::init)
+            // before user::init, whose GET /users/{id} would otherwise swallow these paths
+            .configure(api_key::init)
+            .configure(audit::init)
             .configure(user::init) // init user routes
             .configure(post::init)
-    })
-    .bind((host, port))?;
+            .configure(relation::init)
+            .configure(admin::init)
+            .configure(two_factor::init)
+            .configure(oauth::init)
+            .configure(oidc::init)
+    });
+    let server = match config.server.workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    // on SIGTERM actix stops accepting connections and resolves run() once the workers have
+    // drained or the timeout has passed; SIGINT and SIGQUIT stop it without waiting
+    let server = server
+        .shutdown_timeout(config.server.shutdown_timeout_seconds)
+        .bind((config.server.host.as_str(), config.server.port))?;
 
     info!("Starting server");
-    server.run().await?;
+    run_until_stopped(
+        server.run(),
+        stop_monitor,
+        shutdown_pool,
+        &shutdown_metrics,
+        started,
+    )
+    .await
+}
+
+// the pool is only closed once the last request is done with it
+async fn run_until_stopped(
+    server: Server,
+    stop_monitor: oneshot::Sender<()>,
+    db_pool: PgPool,
+    metrics: &metrics::Metrics,
+    started: Instant,
+) -> Result<()> {
+    let result = server.await;
+
+    let _ = stop_monitor.send(());
+    db_pool.close().await;
+    info!(
+        "server stopped after {}s, {} requests handled, database pool closed",
+        started.elapsed().as_secs(),
+        metrics.requests_handled()
+    );
+
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::client::Client;
+    use actix_web::rt::time::delay_for;
+    use std::time::Duration;
+
+    const SLOW_REQUEST: Duration = Duration::from_secs(1);
+
+    async fn slow() -> impl Responder {
+        delay_for(SLOW_REQUEST).await;
+        HttpResponse::Ok().body("done")
+    }
+
+    #[actix_rt::test]
+    async fn sigterm_lets_requests_in_flight_finish() {
+        let db_pool = test_support::pool().await;
+        let metrics = metrics::Metrics::new(1).unwrap();
+        let (stop_monitor, mut monitor_stopped) = oneshot::channel();
+        let server = HttpServer::new(|| App::new().route("/slow", web::get().to(slow)))
+            .workers(1)
+            .shutdown_timeout(5)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let url = format!("http://{}/slow", server.addrs()[0]);
+
+        let request = async {
+            let mut response = Client::new().get(url).send().await.unwrap();
+            let body = response.body().await.unwrap();
+            (response.status(), body)
+        };
+        // the signal goes out while the request is still being handled
+        let terminate = async {
+            delay_for(SLOW_REQUEST / 4).await;
+            let pid = process::id().to_string();
+            let status = process::Command::new("kill")
+                .args(["-TERM", &pid])
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        let stopped = run_until_stopped(
+            server.run(),
+            stop_monitor,
+            db_pool.clone(),
+            &metrics,
+            Instant::now(),
+        );
+        let ((status, body), (), stopped) = futures::join!(request, terminate, stopped);
 
-    Ok(())
+        assert!(status.is_success());
+        assert_eq!(body, "done");
+        assert!(stopped.is_ok());
+        assert!(db_pool.is_closed());
+        assert!(monitor_stopped.try_recv().is_ok());
+    }
 }
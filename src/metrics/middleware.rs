@@ -0,0 +1,113 @@
+use crate::metrics::Metrics;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use prometheus::IntGauge;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+// wrapped around the whole App, so requests no route matched are counted too
+pub struct RequestMetrics {
+    metrics: Metrics,
+}
+
+impl RequestMetrics {
+    pub fn new(metrics: Metrics) -> RequestMetrics {
+        RequestMetrics { metrics }
+    }
+}
+
+impl<S, B> Transform<S> for RequestMetrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestMetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestMetricsMiddleware {
+            service,
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+    metrics: Metrics,
+}
+
+impl<S, B> Service for RequestMetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let metrics = self.metrics.clone();
+        let method = req.method().to_string();
+        let started = Instant::now();
+        let in_flight = InFlight::new(&metrics);
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let result = fut.await;
+            drop(in_flight);
+
+            // the route pattern rather than the path, ids in paths would make a series per
+            // record; paths that matched nothing share one series for the same reason
+            let (route, status) = match &result {
+                Ok(res) => (
+                    res.request()
+                        .match_pattern()
+                        .unwrap_or_else(|| "unmatched".to_string()),
+                    res.status(),
+                ),
+                Err(err) => ("unknown".to_string(), err.as_response_error().status_code()),
+            };
+            let labels = [method.as_str(), route.as_str(), status.as_str()];
+            metrics.http_requests.with_label_values(&labels).inc();
+            metrics
+                .http_request_duration
+                .with_label_values(&labels)
+                .observe(started.elapsed().as_secs_f64());
+
+            result
+        })
+    }
+}
+
+// decrements on drop, so a request whose client went away isn't counted as in flight forever
+struct InFlight {
+    gauge: IntGauge,
+}
+
+impl InFlight {
+    fn new(metrics: &Metrics) -> InFlight {
+        metrics.http_requests_in_flight.inc();
+        InFlight {
+            gauge: metrics.http_requests_in_flight.clone(),
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
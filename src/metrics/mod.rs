@@ -0,0 +1,129 @@
+mod middleware;
+mod routes;
+
+pub use middleware::RequestMetrics;
+pub use routes::init;
+
+use crate::audit::AuditAction;
+use anyhow::Result;
+use prometheus::core::Collector;
+use prometheus::{Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry};
+use serde_json::Value;
+
+// one registry per process rather than the prometheus default one, so everything exported
+// is listed here; the handles are Arcs inside, clones all count into the same series
+#[derive(Clone)]
+pub struct Metrics {
+    pub registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+    pub http_requests_in_flight: IntGauge,
+    pub db_pool_connections: IntGauge,
+    pub db_pool_idle_connections: IntGauge,
+    pub db_pool_acquire_duration: Histogram,
+    pub logins: IntCounterVec,
+    pub tokens_issued: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new(max_connections: u32) -> Result<Metrics> {
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Requests handled"),
+            &["method", "route", "status"],
+        )?;
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time spent handling requests",
+            ),
+            &["method", "route", "status"],
+        )?;
+        let http_requests_in_flight = IntGauge::new(
+            "http_requests_in_flight",
+            "Requests currently being handled",
+        )?;
+        let db_pool_connections =
+            IntGauge::new("db_pool_connections", "Open database connections")?;
+        let db_pool_idle_connections = IntGauge::new(
+            "db_pool_idle_connections",
+            "Open database connections not in use",
+        )?;
+        let db_pool_max_connections =
+            IntGauge::new("db_pool_max_connections", "Configured database pool size")?;
+        db_pool_max_connections.set(max_connections as i64);
+        // fed by the saturation monitor's probes, see db::spawn_saturation_monitor
+        let db_pool_acquire_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "db_pool_acquire_duration_seconds",
+                "Time waited for a database connection",
+            )
+            .buckets(vec![
+                0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+            ]),
+        )?;
+        let logins = IntCounterVec::new(
+            Opts::new("logins_total", "Login attempts"),
+            &["method", "outcome"],
+        )?;
+        let tokens_issued = IntCounterVec::new(
+            Opts::new(
+                "tokens_issued_total",
+                "Session tokens, API keys and access tokens issued",
+            ),
+            &["kind"],
+        )?;
+
+        let registry = Registry::new();
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(http_request_duration.clone()))?;
+        registry.register(Box::new(http_requests_in_flight.clone()))?;
+        registry.register(Box::new(db_pool_connections.clone()))?;
+        registry.register(Box::new(db_pool_idle_connections.clone()))?;
+        registry.register(Box::new(db_pool_max_connections))?;
+        registry.register(Box::new(db_pool_acquire_duration.clone()))?;
+        registry.register(Box::new(logins.clone()))?;
+        registry.register(Box::new(tokens_issued.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            http_requests,
+            http_request_duration,
+            http_requests_in_flight,
+            db_pool_connections,
+            db_pool_idle_connections,
+            db_pool_acquire_duration,
+            logins,
+            tokens_issued,
+        })
+    }
+
+    // for the summary logged on shutdown
+    pub fn requests_handled(&self) -> u64 {
+        self.http_requests
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_counter().get_value() as u64)
+            .sum()
+    }
+
+    // every login and token issuance is already audited, so they are counted from there
+    // instead of in each of the handlers
+    pub fn observe_audit(&self, action: AuditAction, details: &Value) {
+        let method = details["method"].as_str().unwrap_or("unknown");
+        match action {
+            AuditAction::Login => {
+                self.logins.with_label_values(&[method, "success"]).inc();
+                self.tokens_issued.with_label_values(&["session"]).inc();
+            }
+            AuditAction::LoginFailed => {
+                self.logins.with_label_values(&[method, "failure"]).inc();
+            }
+            AuditAction::TokenCreated => {
+                let kind = details["kind"].as_str().unwrap_or("unknown");
+                self.tokens_issued.with_label_values(&[kind]).inc();
+            }
+            _ => {}
+        }
+    }
+}
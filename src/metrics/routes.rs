@@ -0,0 +1,27 @@
+use crate::metrics::Metrics;
+use actix_web::{get, web, HttpResponse, Responder};
+use log::error;
+use prometheus::{Encoder, TextEncoder};
+use sqlx::PgPool;
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(metrics);
+}
+
+#[get("/metrics")]
+async fn metrics(metrics: web::Data<Metrics>, db_pool: web::Data<PgPool>) -> impl Responder {
+    // the pool keeps its own counts, they only need copying over when scraped
+    let pool = db_pool.get_ref();
+    metrics.db_pool_connections.set(pool.size() as i64);
+    metrics.db_pool_idle_connections.set(pool.num_idle() as i64);
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(err) = encoder.encode(&metrics.registry.gather(), &mut body) {
+        error!("error encoding metrics: {}", err);
+        return HttpResponse::InternalServerError().body("Error trying to encode metrics");
+    }
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(body)
+}
@@ -0,0 +1,479 @@
+use crate::auth::{scopes_from_db, scopes_to_db, Scope};
+use crate::token::{generate_random_u8s, hash_secret};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Done, PgPool};
+use uuid::Uuid;
+
+// lets the auth layer tell OAuth access tokens apart from login tokens and API keys
+const ACCESS_TOKEN_MARKER: &str = "oat_";
+
+#[derive(Serialize, Deserialize)]
+pub struct ClientRequest {
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    // confidential clients get a secret, public ones (SPAs, mobile apps) rely on PKCE alone
+    pub confidential: bool,
+}
+
+// the query of GET /oauth/authorize and the body of POST /oauth/authorize
+#[derive(Serialize, Deserialize)]
+pub struct AuthorizationRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub state: Option<String>,
+    pub code_challenge: String,
+    pub code_challenge_method: String,
+    // only read on POST, where the user approves or denies
+    pub approve: Option<bool>,
+}
+
+// form body of POST /oauth/token
+#[derive(Serialize, Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub code: String,
+    pub redirect_uri: String,
+    pub code_verifier: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+// form body of POST /oauth/introspect and POST /oauth/revoke
+#[derive(Serialize, Deserialize)]
+pub struct TokenActionRequest {
+    pub token: String,
+    pub token_type_hint: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+// RFC 6749 section 5.2 error body, shared by every OAuth endpoint
+#[derive(Serialize)]
+pub struct OAuthError {
+    pub error: &'static str,
+    pub error_description: String,
+}
+
+impl OAuthError {
+    pub fn new(error: &'static str, error_description: &str) -> OAuthError {
+        OAuthError {
+            error,
+            error_description: error_description.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ConsentPrompt {
+    pub client_id: String,
+    pub client_name: String,
+    pub scopes: Vec<Scope>,
+    // the user already agreed to all of these scopes, so the app may skip asking again
+    pub previously_granted: bool,
+}
+
+#[derive(Serialize)]
+pub struct AuthorizationResponse {
+    pub redirect_to: String,
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    pub scope: String,
+}
+
+// RFC 7662, inactive tokens reveal nothing but the fact that they are inactive
+#[derive(Serialize, Default)]
+pub struct IntrospectionResponse {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+pub struct OAuthClient {
+    pub id: Uuid,
+    pub client_id: String,
+    #[serde(skip)]
+    pub secret_hash: Option<String>,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub owner_id: Uuid,
+    pub created_at: DateTime<Utc>,
+}
+
+// the only time the client secret is ever shown
+#[derive(Serialize)]
+pub struct NewOAuthClient {
+    #[serde(flatten)]
+    pub client: OAuthClient,
+    pub client_secret: Option<String>,
+}
+
+pub struct AuthorizationCode {
+    pub user_id: Uuid,
+    pub redirect_uri: String,
+    pub scopes: Vec<Scope>,
+    pub code_challenge: String,
+}
+
+pub struct AccessToken {
+    pub client_id: String,
+    pub user_id: Uuid,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl OAuthClient {
+    pub async fn create(
+        request: ClientRequest,
+        owner_id: Uuid,
+        pool: &PgPool,
+    ) -> Result<NewOAuthClient> {
+        let id = Uuid::new_v4();
+        let client_id = base32::encode(
+            base32::Alphabet::RFC4648 { padding: false },
+            &generate_random_u8s(10),
+        )
+        .to_lowercase();
+        let client_secret = if request.confidential {
+            Some(base64::encode_config(
+                generate_random_u8s(32),
+                base64::URL_SAFE_NO_PAD,
+            ))
+        } else {
+            None
+        };
+        let secret_hash = client_secret.as_deref().map(hash_secret);
+
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO oauth_clients (id, client_id, secret_hash, name, redirect_uris, owner_id)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            id,
+            client_id,
+            secret_hash,
+            request.name,
+            &request.redirect_uris,
+            owner_id,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        let rec = sqlx::query!(
+            r#"
+            SELECT id, client_id, secret_hash, name, redirect_uris, owner_id, created_at
+            FROM oauth_clients
+            WHERE id = $1
+            "#,
+            id,
+        )
+        .fetch_one(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(NewOAuthClient {
+            client: OAuthClient {
+                id: rec.id,
+                client_id: rec.client_id,
+                secret_hash: rec.secret_hash,
+                name: rec.name,
+                redirect_uris: rec.redirect_uris,
+                owner_id: rec.owner_id,
+                created_at: rec.created_at,
+            },
+            client_secret,
+        })
+    }
+
+    pub async fn find_by_client_id(client_id: &str, pool: &PgPool) -> Result<Option<OAuthClient>> {
+        let rec = sqlx::query!(
+            r#"
+            SELECT id, client_id, secret_hash, name, redirect_uris, owner_id, created_at
+            FROM oauth_clients
+            WHERE client_id = $1
+            "#,
+            client_id,
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(rec.map(|rec| OAuthClient {
+            id: rec.id,
+            client_id: rec.client_id,
+            secret_hash: rec.secret_hash,
+            name: rec.name,
+            redirect_uris: rec.redirect_uris,
+            owner_id: rec.owner_id,
+            created_at: rec.created_at,
+        }))
+    }
+
+    pub fn is_confidential(&self) -> bool {
+        self.secret_hash.is_some()
+    }
+
+    // public clients must not send a secret, confidential ones must send the right one
+    pub fn verify_secret(&self, secret: Option<&str>) -> bool {
+        match (&self.secret_hash, secret) {
+            (Some(secret_hash), Some(secret)) => *secret_hash == hash_secret(secret),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+pub struct Consent;
+
+impl Consent {
+    pub async fn find_scopes(
+        user_id: Uuid,
+        client_id: &str,
+        pool: &PgPool,
+    ) -> Result<Option<Vec<Scope>>> {
+        let rec = sqlx::query!(
+            r#"
+            SELECT scopes
+            FROM oauth_consents
+            WHERE user_id = $1 AND client_id = $2
+            "#,
+            user_id,
+            client_id,
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(rec.map(|rec| scopes_from_db(&rec.scopes)))
+    }
+
+    // adds the scopes to whatever the user granted the client before
+    pub async fn grant(
+        user_id: Uuid,
+        client_id: &str,
+        scopes: &[Scope],
+        pool: &PgPool,
+    ) -> Result<()> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO oauth_consents (user_id, client_id, scopes)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (user_id, client_id) DO UPDATE
+            SET scopes = ARRAY(
+                    SELECT DISTINCT unnest(oauth_consents.scopes || EXCLUDED.scopes)
+                ),
+                granted_at = now()
+            "#,
+            user_id,
+            client_id,
+            &scopes_to_db(scopes),
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+impl AuthorizationCode {
+    // only the hash is stored, so the plain code is handed back to the caller
+    pub async fn create(
+        client_id: &str,
+        user_id: Uuid,
+        redirect_uri: &str,
+        scopes: &[Scope],
+        code_challenge: &str,
+        ttl: Duration,
+        pool: &PgPool,
+    ) -> Result<String> {
+        let code = base64::encode_config(generate_random_u8s(32), base64::URL_SAFE_NO_PAD);
+        let expires_at = Utc::now() + ttl;
+
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO oauth_authorization_codes (
+                id, code_hash, client_id, user_id, redirect_uri, scopes, code_challenge,
+                expires_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            "#,
+            Uuid::new_v4(),
+            hash_secret(&code),
+            client_id,
+            user_id,
+            redirect_uri,
+            &scopes_to_db(scopes),
+            code_challenge,
+            expires_at,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(code)
+    }
+
+    // codes are single use, so redeeming one deletes it whether or not the exchange succeeds
+    pub async fn redeem(
+        code: &str,
+        client_id: &str,
+        pool: &PgPool,
+    ) -> Result<Option<AuthorizationCode>> {
+        let mut tx = pool.begin().await?;
+
+        let rec = sqlx::query!(
+            r#"
+            DELETE FROM oauth_authorization_codes
+            WHERE code_hash = $1 AND client_id = $2 AND expires_at > now()
+            RETURNING user_id, redirect_uri, scopes, code_challenge
+            "#,
+            hash_secret(code),
+            client_id,
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(rec.map(|rec| AuthorizationCode {
+            user_id: rec.user_id,
+            redirect_uri: rec.redirect_uri,
+            scopes: scopes_from_db(&rec.scopes),
+            code_challenge: rec.code_challenge,
+        }))
+    }
+
+    // RFC 7636 S256: the challenge is the unpadded base64url SHA-256 of the verifier
+    pub fn verify_pkce(&self, code_verifier: &str) -> bool {
+        let len = code_verifier.len();
+        if !(43..=128).contains(&len) {
+            return false;
+        }
+        let digest = Sha256::digest(code_verifier.as_bytes());
+        base64::encode_config(digest, base64::URL_SAFE_NO_PAD) == self.code_challenge
+    }
+}
+
+impl AccessToken {
+    pub fn is_access_token(token: &str) -> bool {
+        token.starts_with(ACCESS_TOKEN_MARKER)
+    }
+
+    // only the hash is stored, so the plain token is handed back to the caller
+    pub async fn create(
+        client_id: &str,
+        user_id: Uuid,
+        scopes: &[Scope],
+        ttl: Duration,
+        pool: &PgPool,
+    ) -> Result<String> {
+        let token = format!(
+            "{}{}",
+            ACCESS_TOKEN_MARKER,
+            base64::encode_config(generate_random_u8s(32), base64::URL_SAFE_NO_PAD)
+        );
+        let expires_at = Utc::now() + ttl;
+
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO oauth_access_tokens (id, token_hash, client_id, user_id, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            Uuid::new_v4(),
+            hash_secret(&token),
+            client_id,
+            user_id,
+            &scopes_to_db(scopes),
+            expires_at,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(token)
+    }
+
+    // finds the token whatever its state, see is_active
+    pub async fn find_by_value(token: &str, pool: &PgPool) -> Result<Option<AccessToken>> {
+        let rec = sqlx::query!(
+            r#"
+            SELECT client_id, user_id, scopes, created_at, expires_at, revoked_at
+            FROM oauth_access_tokens
+            WHERE token_hash = $1
+            "#,
+            hash_secret(token),
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(rec.map(|rec| AccessToken {
+            client_id: rec.client_id,
+            user_id: rec.user_id,
+            scopes: scopes_from_db(&rec.scopes),
+            created_at: rec.created_at,
+            expires_at: rec.expires_at,
+            revoked_at: rec.revoked_at,
+        }))
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.revoked_at.is_none() && self.expires_at > Utc::now()
+    }
+
+    // only the client the token was issued to may revoke it
+    pub async fn revoke(token: &str, client_id: &str, pool: &PgPool) -> Result<u64> {
+        let mut tx = pool.begin().await?;
+
+        let n_revoked = sqlx::query!(
+            r#"
+            UPDATE oauth_access_tokens
+            SET revoked_at = now()
+            WHERE token_hash = $1 AND client_id = $2 AND revoked_at IS NULL
+            "#,
+            hash_secret(token),
+            client_id,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(n_revoked)
+    }
+}
@@ -0,0 +1,619 @@
+use crate::audit::{self, AuditAction};
+use crate::auth::{self, format_scope_list, parse_scope_list, Scope};
+use crate::config::TokenConfig;
+use crate::oauth::{
+    AccessToken, AuthorizationCode, AuthorizationRequest, AuthorizationResponse, ClientRequest,
+    Consent, ConsentPrompt, IntrospectionResponse, OAuthClient, OAuthError, TokenActionRequest,
+    TokenRequest, TokenResponse,
+};
+use crate::user::User;
+use actix_web::http::header;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use log::error;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde_json::json;
+use sqlx::PgPool;
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_client)
+        .service(authorize_prompt)
+        .service(authorize)
+        .service(token)
+        .service(introspect)
+        .service(revoke);
+}
+
+#[post("/oauth/clients")]
+async fn create_client(
+    credentials: BearerAuth,
+    request: web::Json<ClientRequest>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result = auth::validate_bearer_auth(credentials, Scope::Account, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    if request.name.trim().is_empty() {
+        return HttpResponse::BadRequest().body("A client needs a name");
+    }
+    if request.redirect_uris.is_empty() {
+        return HttpResponse::BadRequest().body("A client needs at least one redirect URI");
+    }
+    if let Some(uri) = request
+        .redirect_uris
+        .iter()
+        .find(|uri| !is_valid_redirect_uri(uri))
+    {
+        let msg = format!(
+            "{} is not a valid redirect URI, it must use https (or http on localhost) \
+             and have no fragment",
+            uri
+        );
+        return HttpResponse::BadRequest().body(msg);
+    }
+
+    let result = OAuthClient::create(request.into_inner(), user.id, db_pool.get_ref()).await;
+    match result {
+        Ok(client) => HttpResponse::Ok().json(client),
+        Err(err) => {
+            error!("error creating oauth client: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to create new OAuth client")
+        }
+    }
+}
+
+// what the app shows the user before they approve or deny the request
+#[get("/oauth/authorize")]
+async fn authorize_prompt(
+    credentials: BearerAuth,
+    request: web::Query<AuthorizationRequest>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result = auth::validate_bearer_auth(credentials, Scope::Account, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    let (client, scopes) = match check_authorization_request(&request, db_pool.get_ref()).await {
+        Ok(checked) => checked,
+        Err(response) => return response,
+    };
+
+    let result = Consent::find_scopes(user.id, &client.client_id, db_pool.get_ref()).await;
+    match result {
+        Ok(granted) => {
+            let previously_granted =
+                granted.is_some_and(|granted| scopes.iter().all(|scope| granted.contains(scope)));
+            HttpResponse::Ok().json(ConsentPrompt {
+                client_id: client.client_id,
+                client_name: client.name,
+                scopes,
+                previously_granted,
+            })
+        }
+        Err(err) => {
+            error!("error finding oauth consent: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to find consent")
+        }
+    }
+}
+
+// records the user's decision and tells the app where to send the browser next
+#[post("/oauth/authorize")]
+async fn authorize(
+    credentials: BearerAuth,
+    request: web::Json<AuthorizationRequest>,
+    tokens: web::Data<TokenConfig>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result = auth::validate_bearer_auth(credentials, Scope::Account, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    let (client, scopes) = match check_authorization_request(&request, db_pool.get_ref()).await {
+        Ok(checked) => checked,
+        Err(response) => return response,
+    };
+
+    if !request.approve.unwrap_or(false) {
+        let params = vec![("error", "access_denied".to_string())];
+        let redirect_to = redirect_with(&request.redirect_uri, params, &request.state);
+        return HttpResponse::Ok().json(AuthorizationResponse { redirect_to });
+    }
+
+    let result = Consent::grant(user.id, &client.client_id, &scopes, db_pool.get_ref()).await;
+    if let Err(err) = result {
+        error!("error recording oauth consent: {}", err);
+        return HttpResponse::InternalServerError().body("Error trying to record consent");
+    }
+
+    let result = AuthorizationCode::create(
+        &client.client_id,
+        user.id,
+        &request.redirect_uri,
+        &scopes,
+        &request.code_challenge,
+        tokens.oauth_code_ttl(),
+        db_pool.get_ref(),
+    )
+    .await;
+    match result {
+        Ok(code) => {
+            let params = vec![("code", code)];
+            let redirect_to = redirect_with(&request.redirect_uri, params, &request.state);
+            HttpResponse::Ok().json(AuthorizationResponse { redirect_to })
+        }
+        Err(err) => {
+            error!("error creating authorization code: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to create authorization code")
+        }
+    }
+}
+
+#[post("/oauth/token")]
+async fn token(
+    req: HttpRequest,
+    basic: Option<BasicAuth>,
+    request: web::Form<TokenRequest>,
+    tokens: web::Data<TokenConfig>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let client = match authenticate_client(
+        basic,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+        db_pool.get_ref(),
+    )
+    .await
+    {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+
+    if request.grant_type != "authorization_code" {
+        return HttpResponse::BadRequest().json(OAuthError::new(
+            "unsupported_grant_type",
+            "Only the authorization_code grant is supported",
+        ));
+    }
+
+    let result =
+        AuthorizationCode::redeem(&request.code, &client.client_id, db_pool.get_ref()).await;
+    let code = match result {
+        Ok(Some(code)) => code,
+        Ok(None) => {
+            return HttpResponse::BadRequest().json(OAuthError::new(
+                "invalid_grant",
+                "The authorization code is invalid, expired or already used",
+            ));
+        }
+        Err(err) => {
+            error!("error redeeming authorization code: {}", err);
+            return HttpResponse::InternalServerError().body("Error trying to redeem code");
+        }
+    };
+    if code.redirect_uri != request.redirect_uri {
+        return HttpResponse::BadRequest().json(OAuthError::new(
+            "invalid_grant",
+            "redirect_uri does not match the authorization request",
+        ));
+    }
+    if !code.verify_pkce(&request.code_verifier) {
+        return HttpResponse::BadRequest().json(OAuthError::new(
+            "invalid_grant",
+            "code_verifier does not match the code_challenge",
+        ));
+    }
+
+    let ttl = tokens.oauth_access_token_ttl();
+    let result = AccessToken::create(
+        &client.client_id,
+        code.user_id,
+        &code.scopes,
+        ttl,
+        db_pool.get_ref(),
+    )
+    .await;
+    match result {
+        Ok(access_token) => {
+            let details = json!({
+                "kind": "oauth_access_token",
+                "client_id": client.client_id,
+                "scopes": &code.scopes,
+            });
+            audit::record(
+                &req,
+                AuditAction::TokenCreated,
+                Some(code.user_id),
+                None,
+                details,
+                db_pool.get_ref(),
+            )
+            .await;
+            HttpResponse::Ok()
+                .header(header::CACHE_CONTROL, "no-store")
+                .json(TokenResponse {
+                    access_token,
+                    token_type: "Bearer",
+                    expires_in: ttl.num_seconds(),
+                    scope: format_scope_list(&code.scopes),
+                })
+        }
+        Err(err) => {
+            error!("error creating oauth access token: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to create access token")
+        }
+    }
+}
+
+// RFC 7662; only confidential clients may introspect, and only their own tokens
+#[post("/oauth/introspect")]
+async fn introspect(
+    basic: Option<BasicAuth>,
+    request: web::Form<TokenActionRequest>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let client = match authenticate_client(
+        basic,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+        db_pool.get_ref(),
+    )
+    .await
+    {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    if !client.is_confidential() {
+        return HttpResponse::Unauthorized().json(OAuthError::new(
+            "invalid_client",
+            "Only confidential clients may introspect tokens",
+        ));
+    }
+
+    let inactive = IntrospectionResponse::default();
+    let result = AccessToken::find_by_value(&request.token, db_pool.get_ref()).await;
+    let access_token = match result {
+        Ok(Some(access_token))
+            if access_token.is_active() && access_token.client_id == client.client_id =>
+        {
+            access_token
+        }
+        Ok(_) => return HttpResponse::Ok().json(inactive),
+        Err(err) => {
+            error!("error finding oauth access token: {}", err);
+            return HttpResponse::InternalServerError().body("Error trying to introspect token");
+        }
+    };
+
+    // a suspended user's tokens stop working, so they are reported as inactive too
+    let result = User::find_by_id(access_token.user_id, db_pool.get_ref()).await;
+    match result {
+        Ok(Some(user)) if user.disabled_at.is_none() => {
+            HttpResponse::Ok().json(IntrospectionResponse {
+                active: true,
+                scope: Some(format_scope_list(&access_token.scopes)),
+                client_id: Some(access_token.client_id),
+                username: Some(user.username),
+                sub: Some(user.id),
+                exp: Some(access_token.expires_at.timestamp()),
+                iat: Some(access_token.created_at.timestamp()),
+                token_type: Some("Bearer"),
+            })
+        }
+        Ok(_) => HttpResponse::Ok().json(inactive),
+        Err(err) => {
+            error!("error finding user: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to introspect token")
+        }
+    }
+}
+
+// RFC 7009; unknown or foreign tokens still get a 200 so clients learn nothing from the answer
+#[post("/oauth/revoke")]
+async fn revoke(
+    basic: Option<BasicAuth>,
+    request: web::Form<TokenActionRequest>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let client = match authenticate_client(
+        basic,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+        db_pool.get_ref(),
+    )
+    .await
+    {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+
+    let result = AccessToken::revoke(&request.token, &client.client_id, db_pool.get_ref()).await;
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(err) => {
+            error!("error revoking oauth access token: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to revoke token")
+        }
+    }
+}
+
+// checks everything that has to be right before it is safe to redirect back to the client
+async fn check_authorization_request(
+    request: &AuthorizationRequest,
+    pool: &PgPool,
+) -> Result<(OAuthClient, Vec<Scope>), HttpResponse> {
+    let client = match OAuthClient::find_by_client_id(&request.client_id, pool).await {
+        Ok(Some(client)) => client,
+        Ok(None) => {
+            return Err(HttpResponse::BadRequest()
+                .json(OAuthError::new("invalid_client", "Unknown client_id")));
+        }
+        Err(err) => {
+            error!("error finding oauth client: {}", err);
+            return Err(HttpResponse::InternalServerError().body("Error trying to find client"));
+        }
+    };
+    if !client.redirect_uris.contains(&request.redirect_uri) {
+        return Err(HttpResponse::BadRequest().json(OAuthError::new(
+            "invalid_request",
+            "redirect_uri is not registered for this client",
+        )));
+    }
+    if request.response_type != "code" {
+        return Err(HttpResponse::BadRequest().json(OAuthError::new(
+            "unsupported_response_type",
+            "Only the code response type is supported",
+        )));
+    }
+    if request.code_challenge_method != "S256" || request.code_challenge.is_empty() {
+        return Err(HttpResponse::BadRequest().json(OAuthError::new(
+            "invalid_request",
+            "A code_challenge with the S256 method is required",
+        )));
+    }
+
+    let scopes = match parse_scope_list(&request.scope) {
+        Some(scopes) if !scopes.is_empty() => scopes,
+        _ => {
+            return Err(HttpResponse::BadRequest()
+                .json(OAuthError::new("invalid_scope", "Unknown or empty scope")));
+        }
+    };
+    if let Some(scope) = scopes.iter().find(|scope| !scope.is_delegable()) {
+        let msg = format!("The {} scope can't be granted to a client", scope);
+        return Err(HttpResponse::BadRequest().json(OAuthError::new("invalid_scope", &msg)));
+    }
+
+    Ok((client, scopes))
+}
+
+// credentials may come as HTTP Basic or as form fields (RFC 6749 section 2.3.1)
+async fn authenticate_client(
+    basic: Option<BasicAuth>,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+    pool: &PgPool,
+) -> Result<OAuthClient, HttpResponse> {
+    let (client_id, client_secret) = match &basic {
+        Some(basic) => (
+            Some(&**basic.user_id()),
+            basic.password().map(|password| &**password),
+        ),
+        None => (client_id, client_secret),
+    };
+    let invalid_client = || {
+        HttpResponse::Unauthorized().json(OAuthError::new(
+            "invalid_client",
+            "Client authentication failed",
+        ))
+    };
+
+    let client_id = match client_id {
+        Some(client_id) => client_id,
+        None => return Err(invalid_client()),
+    };
+    match OAuthClient::find_by_client_id(client_id, pool).await {
+        Ok(Some(client)) if client.verify_secret(client_secret) => Ok(client),
+        Ok(_) => Err(invalid_client()),
+        Err(err) => {
+            error!("error finding oauth client: {}", err);
+            Err(HttpResponse::InternalServerError().body("Error trying to find client"))
+        }
+    }
+}
+
+fn is_valid_redirect_uri(uri: &str) -> bool {
+    let loopback = ["http://localhost", "http://127.0.0.1"].iter().any(|host| {
+        uri.strip_prefix(host)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with([':', '/']))
+    });
+    (uri.starts_with("https://") || loopback) && !uri.contains('#')
+}
+
+fn redirect_with(
+    redirect_uri: &str,
+    mut params: Vec<(&str, String)>,
+    state: &Option<String>,
+) -> String {
+    if let Some(state) = state {
+        params.push(("state", state.clone()));
+    }
+    let query = params
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, utf8_percent_encode(value, NON_ALPHANUMERIC)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", redirect_uri, separator, query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{EmailConfig, TokenConfig};
+    use crate::email_verification::EmailSettings;
+    use crate::mail::{Mailer, StdoutMailer};
+    use crate::test_support;
+    use crate::token::Token;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::Value;
+    use sha2::{Digest, Sha256};
+    use std::sync::Arc;
+
+    const REDIRECT_URI: &str = "https://app.example/callback";
+    const CODE_VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+
+    #[actix_rt::test]
+    async fn authorization_code_flow() {
+        let pool = test_support::pool().await;
+        let user = test_support::create_user(&pool).await;
+        let login = Token::create(user.id, &pool).await.unwrap();
+        let bearer = format!("Bearer {}", login.value);
+
+        let mailer: Arc<dyn Mailer> = Arc::new(StdoutMailer);
+        let tokens = TokenConfig::default();
+        let email_settings = EmailSettings::from_config(&EmailConfig::default(), &tokens);
+        let mut app = test::init_service(
+            App::new()
+                .data(pool.clone())
+                .data(mailer)
+                .data(email_settings)
+                .data(tokens)
+                .configure(crate::user::init)
+                .configure(init),
+        )
+        .await;
+
+        // the user registers a confidential client...
+        let req = test::TestRequest::post()
+            .uri("/oauth/clients")
+            .header(header::AUTHORIZATION, bearer.as_str())
+            .set_json(&json!({
+                "name": "Test App",
+                "redirect_uris": [REDIRECT_URI],
+                "confidential": true,
+            }))
+            .to_request();
+        let client: Value = test::read_response_json(&mut app, req).await;
+        let client_id = client["client_id"].as_str().unwrap().to_string();
+        let client_secret = client["client_secret"].as_str().unwrap().to_string();
+
+        // ...and approves it for posts:read and users:write
+        let code_challenge = base64::encode_config(
+            Sha256::digest(CODE_VERIFIER.as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let req = test::TestRequest::post()
+            .uri("/oauth/authorize")
+            .header(header::AUTHORIZATION, bearer.as_str())
+            .set_json(&json!({
+                "response_type": "code",
+                "client_id": client_id,
+                "redirect_uri": REDIRECT_URI,
+                "scope": "posts:read users:write",
+                "state": "xyz",
+                "code_challenge": code_challenge,
+                "code_challenge_method": "S256",
+                "approve": true,
+            }))
+            .to_request();
+        let authorized: Value = test::read_response_json(&mut app, req).await;
+        let redirect_to = authorized["redirect_to"].as_str().unwrap();
+        let query = redirect_to
+            .strip_prefix(&format!("{}?", REDIRECT_URI))
+            .unwrap();
+        assert!(query.ends_with("&state=xyz"), "{}", redirect_to);
+        let code = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("code="))
+            .unwrap();
+        let code = percent_encoding::percent_decode_str(code)
+            .decode_utf8()
+            .unwrap()
+            .to_string();
+
+        let token_form = [
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("redirect_uri", REDIRECT_URI),
+            ("code_verifier", CODE_VERIFIER),
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.as_str()),
+        ];
+        let req = test::TestRequest::post()
+            .uri("/oauth/token")
+            .set_form(&token_form)
+            .to_request();
+        let issued: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(issued["scope"], "posts:read users:write");
+        let access_token = issued["access_token"].as_str().unwrap().to_string();
+
+        // a code is good for one token only
+        let req = test::TestRequest::post()
+            .uri("/oauth/token")
+            .set_form(&token_form)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let token_action = [
+            ("token", access_token.as_str()),
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.as_str()),
+        ];
+        let req = test::TestRequest::post()
+            .uri("/oauth/introspect")
+            .set_form(&token_action)
+            .to_request();
+        let introspected: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(introspected["active"], true);
+        assert_eq!(introspected["username"], user.username.as_str());
+        assert_eq!(introspected["scope"], "posts:read users:write");
+
+        // users:write is not enough to move the address password resets are sent to
+        let req = test::TestRequest::put()
+            .uri("/users")
+            .header(header::AUTHORIZATION, format!("Bearer {}", access_token))
+            .set_json(&json!({
+                "name": "Taken Over",
+                "username": user.username,
+                "email": "attacker@example.com",
+            }))
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let unchanged = User::find_by_id(user.id, &pool).await.unwrap().unwrap();
+        assert_eq!(unchanged.email, None);
+
+        let req = test::TestRequest::post()
+            .uri("/oauth/revoke")
+            .set_form(&token_action)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/oauth/introspect")
+            .set_form(&token_action)
+            .to_request();
+        let introspected: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(introspected, json!({ "active": false }));
+
+        User::delete(user.id, &pool).await.unwrap();
+    }
+}
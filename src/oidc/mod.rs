@@ -0,0 +1,9 @@
+mod model;
+mod provider;
+mod routes;
+#[cfg(test)]
+mod test_provider;
+
+pub use model::*;
+pub use provider::*;
+pub use routes::init;
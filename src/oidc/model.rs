@@ -0,0 +1,146 @@
+use crate::token::{generate_random_u8s, hash_secret};
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Done, PgPool};
+use uuid::Uuid;
+
+// query of the provider's redirect back to us
+#[derive(Serialize, Deserialize)]
+pub struct CallbackRequest {
+    pub state: String,
+    pub code: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct OidcLoginResponse {
+    pub authorization_url: String,
+}
+
+// everything the callback needs to finish the login it belongs to
+pub struct OidcLoginState {
+    pub nonce: String,
+    pub code_verifier: String,
+    // set when a signed in user started the flow to link the identity to their account
+    pub link_user_id: Option<Uuid>,
+}
+
+pub struct NewOidcLogin {
+    pub state: String,
+    pub nonce: String,
+    pub code_challenge: String,
+}
+
+impl OidcLoginState {
+    // only the hash of the state is stored, so the plain values are handed back to the caller
+    pub async fn create(
+        link_user_id: Option<Uuid>,
+        ttl: Duration,
+        pool: &PgPool,
+    ) -> Result<NewOidcLogin> {
+        let state = base64::encode_config(generate_random_u8s(24), base64::URL_SAFE_NO_PAD);
+        let nonce = base64::encode_config(generate_random_u8s(24), base64::URL_SAFE_NO_PAD);
+        let code_verifier = base64::encode_config(generate_random_u8s(32), base64::URL_SAFE_NO_PAD);
+        let code_challenge = base64::encode_config(
+            Sha256::digest(code_verifier.as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let expires_at = Utc::now() + ttl;
+
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO oidc_login_states
+                (id, state_hash, nonce, code_verifier, link_user_id, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            Uuid::new_v4(),
+            hash_secret(&state),
+            nonce,
+            code_verifier,
+            link_user_id,
+            expires_at,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(NewOidcLogin {
+            state,
+            nonce,
+            code_challenge,
+        })
+    }
+
+    // a state can only be used once, whether or not the login succeeds
+    pub async fn redeem(state: &str, pool: &PgPool) -> Result<Option<OidcLoginState>> {
+        let mut tx = pool.begin().await?;
+
+        let rec = sqlx::query!(
+            r#"
+            DELETE FROM oidc_login_states
+            WHERE state_hash = $1 AND expires_at > now()
+            RETURNING nonce, code_verifier, link_user_id
+            "#,
+            hash_secret(state),
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(rec.map(|rec| OidcLoginState {
+            nonce: rec.nonce,
+            code_verifier: rec.code_verifier,
+            link_user_id: rec.link_user_id,
+        }))
+    }
+}
+
+pub struct OidcIdentity;
+
+impl OidcIdentity {
+    pub async fn find_user_id(issuer: &str, subject: &str, pool: &PgPool) -> Result<Option<Uuid>> {
+        let rec = sqlx::query!(
+            r#"
+            SELECT user_id
+            FROM oidc_identities
+            WHERE issuer = $1 AND subject = $2
+            "#,
+            issuer,
+            subject,
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(rec.map(|rec| rec.user_id))
+    }
+
+    // false when the subject is already linked, to this user or another one
+    pub async fn link(issuer: &str, subject: &str, user_id: Uuid, pool: &PgPool) -> Result<bool> {
+        let mut tx = pool.begin().await?;
+
+        let n_inserted = sqlx::query!(
+            r#"
+            INSERT INTO oidc_identities (issuer, subject, user_id)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING
+            "#,
+            issuer,
+            subject,
+            user_id,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(n_inserted > 0)
+    }
+}
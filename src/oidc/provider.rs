@@ -0,0 +1,327 @@
+// OpenID Connect relying party: discovery, the authorization code exchange and ID token
+// validation against the provider's JWKS
+use crate::config::OidcConfig;
+use crate::telemetry;
+use actix_web::client::Client;
+use anyhow::{anyhow, Result};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+const SCOPES: &str = "openid email profile";
+// clock skew tolerated when checking exp and iat
+const LEEWAY_SECONDS: u64 = 60;
+// an unknown key id refetches the keys at most this often, otherwise anyone able to reach
+// the callback could have us hammer the provider with made-up key ids
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct OidcSettings {
+    // e.g. https://sso.example.com, discovery lives under /.well-known/openid-configuration
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    // where the provider sends the browser back to, it has to reach /users/login/oidc/callback
+    pub redirect_url: String,
+    // create an account on the first login of an unknown subject instead of refusing it
+    pub auto_provision: bool,
+}
+
+impl OidcSettings {
+    // OIDC login stays off unless an issuer is configured; Config::load has already made
+    // sure the client id and redirect URL come with it
+    pub fn from_config(config: &OidcConfig) -> Option<OidcSettings> {
+        let issuer_url = config.issuer_url.as_ref()?;
+        Some(OidcSettings {
+            issuer_url: issuer_url.trim_end_matches('/').to_string(),
+            client_id: config.client_id.clone()?,
+            client_secret: config.client_secret.clone(),
+            redirect_url: config.redirect_url.clone()?,
+            auto_provision: config.auto_provision,
+        })
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Clone, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Default)]
+struct JwkCache {
+    keys: Vec<Jwk>,
+    // when the last fetch was started, whether or not it worked
+    fetched_at: Option<Instant>,
+}
+
+#[derive(Deserialize)]
+struct TokenEndpointResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub name: Option<String>,
+    pub preferred_username: Option<String>,
+}
+
+pub struct OidcProvider {
+    pub settings: OidcSettings,
+    // both documents are fetched on first use; the keys are refetched when the provider
+    // signs with a key id we have not seen, which is how key rotation shows up
+    metadata: RwLock<Option<ProviderMetadata>>,
+    jwks: RwLock<JwkCache>,
+}
+
+impl OidcProvider {
+    pub fn from_config(config: &OidcConfig) -> Option<OidcProvider> {
+        OidcSettings::from_config(config).map(|settings| OidcProvider {
+            settings,
+            metadata: RwLock::new(None),
+            jwks: RwLock::new(JwkCache::default()),
+        })
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.settings.issuer_url
+    }
+
+    pub async fn metadata(&self) -> Result<ProviderMetadata> {
+        if let Some(metadata) = self.metadata.read().unwrap().clone() {
+            return Ok(metadata);
+        }
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.settings.issuer_url
+        );
+        let metadata: ProviderMetadata = get_json(&url).await?;
+        // OIDC Discovery section 4.3, the document must be about the issuer we asked for
+        if metadata.issuer.trim_end_matches('/') != self.settings.issuer_url {
+            return Err(anyhow!(
+                "discovery document is for issuer {}, expected {}",
+                metadata.issuer,
+                self.settings.issuer_url
+            ));
+        }
+
+        *self.metadata.write().unwrap() = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    pub async fn authorization_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+    ) -> Result<String> {
+        let metadata = self.metadata().await?;
+        let params = [
+            ("response_type", "code"),
+            ("client_id", self.settings.client_id.as_str()),
+            ("redirect_uri", self.settings.redirect_url.as_str()),
+            ("scope", SCOPES),
+            ("state", state),
+            ("nonce", nonce),
+            ("code_challenge", code_challenge),
+            ("code_challenge_method", "S256"),
+        ];
+        let query = params
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, utf8_percent_encode(value, NON_ALPHANUMERIC)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let separator = if metadata.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        Ok(format!(
+            "{}{}{}",
+            metadata.authorization_endpoint, separator, query
+        ))
+    }
+
+    // trades the code for tokens and returns the validated ID token claims
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims> {
+        let metadata = self.metadata().await?;
+        let mut params = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.settings.redirect_url.as_str()),
+            ("client_id", self.settings.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(client_secret) = &self.settings.client_secret {
+            params.push(("client_secret", client_secret.as_str()));
+        }
+
+        let mut response = client()
+            .post(&metadata.token_endpoint)
+            .send_form(&params)
+            .await
+            .map_err(|err| anyhow!("error calling token endpoint: {}", err))?;
+        if !response.status().is_success() {
+            return Err(anyhow!("token endpoint answered {}", response.status()));
+        }
+        let tokens: TokenEndpointResponse = response
+            .json()
+            .await
+            .map_err(|err| anyhow!("error reading token endpoint response: {}", err))?;
+
+        let claims = self.validate_id_token(&tokens.id_token, &metadata).await?;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(anyhow!("ID token nonce does not match the login"));
+        }
+        Ok(claims)
+    }
+
+    async fn validate_id_token(
+        &self,
+        id_token: &str,
+        metadata: &ProviderMetadata,
+    ) -> Result<IdTokenClaims> {
+        let header = decode_header(id_token)?;
+        if header.alg != Algorithm::RS256 {
+            return Err(anyhow!("unsupported ID token algorithm {:?}", header.alg));
+        }
+
+        let jwk = match self.find_key(header.kid.as_deref()) {
+            Some(jwk) => jwk,
+            None if self.refresh_keys(metadata).await? => self
+                .find_key(header.kid.as_deref())
+                .ok_or_else(|| anyhow!("no signing key matches the ID token"))?,
+            None => return Err(anyhow!("no signing key matches the ID token")),
+        };
+        let (n, e) = match (&jwk.n, &jwk.e) {
+            (Some(n), Some(e)) => (n, e),
+            _ => return Err(anyhow!("signing key has no RSA components")),
+        };
+
+        let mut validation = Validation {
+            algorithms: vec![Algorithm::RS256],
+            iss: Some(metadata.issuer.clone()),
+            leeway: LEEWAY_SECONDS,
+            ..Validation::default()
+        };
+        validation.set_audience(&[&self.settings.client_id]);
+
+        let key = DecodingKey::from_rsa_components(n, e);
+        let data = decode::<IdTokenClaims>(id_token, &key, &validation)?;
+        Ok(data.claims)
+    }
+
+    fn find_key(&self, kid: Option<&str>) -> Option<Jwk> {
+        let keys = &self.jwks.read().unwrap().keys;
+        let mut rsa_keys = keys.iter().filter(|jwk| jwk.kty == "RSA");
+        match kid {
+            Some(kid) => rsa_keys
+                .find(|jwk| jwk.kid.as_deref() == Some(kid))
+                .cloned(),
+            // without a key id the provider must be publishing a single key
+            None if keys.len() == 1 => rsa_keys.next().cloned(),
+            None => None,
+        }
+    }
+
+    // false when the keys were fetched too recently to try again
+    async fn refresh_keys(&self, metadata: &ProviderMetadata) -> Result<bool> {
+        {
+            // claimed before fetching, so concurrent callbacks don't all fetch at once
+            let mut cache = self.jwks.write().unwrap();
+            if let Some(fetched_at) = cache.fetched_at {
+                if fetched_at.elapsed() < JWKS_REFRESH_INTERVAL {
+                    return Ok(false);
+                }
+            }
+            cache.fetched_at = Some(Instant::now());
+        }
+
+        let jwks: JwkSet = get_json(&metadata.jwks_uri).await?;
+        self.jwks.write().unwrap().keys = jwks.keys;
+        Ok(true)
+    }
+}
+
+// carries the current trace on to the provider
+fn client() -> Client {
+    let mut builder = Client::builder();
+    for (name, value) in telemetry::trace_headers() {
+        builder = builder.header(name.as_str(), value);
+    }
+    builder.finish()
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(url: &str) -> Result<T> {
+    let mut response = client()
+        .get(url)
+        .send()
+        .await
+        .map_err(|err| anyhow!("error fetching {}: {}", url, err))?;
+    if !response.status().is_success() {
+        return Err(anyhow!("{} answered {}", url, response.status()));
+    }
+    response
+        .json()
+        .limit(1 << 20)
+        .await
+        .map_err(|err| anyhow!("error reading {}: {}", url, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oidc::test_provider::{sign_id_token, TestProvider, KEY_ID};
+
+    #[actix_rt::test]
+    async fn unknown_key_ids_refetch_the_keys_at_most_once_per_interval() {
+        let idp = TestProvider::start();
+        let provider = idp.provider(true);
+        let metadata = provider.metadata().await.unwrap();
+
+        let id_token = sign_id_token(&idp.issuer(), "subject", "nonce", KEY_ID);
+        let claims = provider.validate_id_token(&id_token, &metadata).await;
+        assert_eq!(claims.unwrap().sub, "subject");
+        assert_eq!(idp.jwks_fetches(), 1);
+
+        // a key id the provider never published, as a forged or rotated token would carry
+        let id_token = sign_id_token(&idp.issuer(), "subject", "nonce", "unknown");
+        for _ in 0..3 {
+            let claims = provider.validate_id_token(&id_token, &metadata).await;
+            assert!(claims.is_err());
+        }
+        assert_eq!(idp.jwks_fetches(), 1);
+
+        let fetched_at = Instant::now().checked_sub(JWKS_REFRESH_INTERVAL).unwrap();
+        provider.jwks.write().unwrap().fetched_at = Some(fetched_at);
+        let claims = provider.validate_id_token(&id_token, &metadata).await;
+        assert!(claims.is_err());
+        assert_eq!(idp.jwks_fetches(), 2);
+    }
+}
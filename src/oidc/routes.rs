@@ -0,0 +1,371 @@
+use crate::audit::{self, AuditAction};
+use crate::auth::{self, Scope};
+use crate::config::TokenConfig;
+use crate::oidc::{
+    CallbackRequest, IdTokenClaims, OidcIdentity, OidcLoginResponse, OidcLoginState, OidcProvider,
+};
+use crate::password::Passwords;
+use crate::token::{generate_random_u8s, Token};
+use crate::two_factor::{LoginChallenge, LoginChallengeResponse, TotpCredential};
+use crate::user::{User, UserPostRequest};
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use anyhow::Result;
+use log::{error, warn};
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(login).service(callback);
+}
+
+// starts a login at the identity provider; with credentials it links the identity to the
+// signed in account instead
+#[get("/users/login/oidc")]
+async fn login(
+    credentials: Option<BearerAuth>,
+    oidc: web::Data<Option<OidcProvider>>,
+    tokens: web::Data<TokenConfig>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let provider = match oidc.get_ref() {
+        Some(provider) => provider,
+        None => return HttpResponse::NotFound().body("OIDC login is not configured"),
+    };
+
+    let link_user_id = match credentials {
+        Some(credentials) => {
+            let result =
+                auth::validate_bearer_auth(credentials, Scope::Account, db_pool.get_ref()).await;
+            match result {
+                Ok(user) => Some(user.id),
+                Err(err) => {
+                    return HttpResponse::from_error(err);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let ttl = tokens.oidc_login_ttl();
+    let login_state = match OidcLoginState::create(link_user_id, ttl, db_pool.get_ref()).await {
+        Ok(login_state) => login_state,
+        Err(err) => {
+            error!("error creating oidc login state: {}", err);
+            return HttpResponse::InternalServerError().body("Error trying to start OIDC login");
+        }
+    };
+
+    let result = provider
+        .authorization_url(
+            &login_state.state,
+            &login_state.nonce,
+            &login_state.code_challenge,
+        )
+        .await;
+    match result {
+        Ok(authorization_url) => HttpResponse::Ok().json(OidcLoginResponse { authorization_url }),
+        Err(err) => {
+            error!("error reading oidc provider metadata: {}", err);
+            HttpResponse::BadGateway().body("Error trying to reach the identity provider")
+        }
+    }
+}
+
+#[get("/users/login/oidc/callback")]
+async fn callback(
+    req: HttpRequest,
+    request: web::Query<CallbackRequest>,
+    oidc: web::Data<Option<OidcProvider>>,
+    passwords: web::Data<Passwords>,
+    tokens: web::Data<TokenConfig>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let provider = match oidc.get_ref() {
+        Some(provider) => provider,
+        None => return HttpResponse::NotFound().body("OIDC login is not configured"),
+    };
+
+    let login_state = match OidcLoginState::redeem(&request.state, db_pool.get_ref()).await {
+        Ok(Some(login_state)) => login_state,
+        Ok(None) => return HttpResponse::BadRequest().body("Unknown or expired login"),
+        Err(err) => {
+            error!("error redeeming oidc login state: {}", err);
+            return HttpResponse::InternalServerError().body("Error trying to finish OIDC login");
+        }
+    };
+
+    if let Some(error) = &request.error {
+        let msg = format!("The identity provider refused the login: {}", error);
+        return HttpResponse::Unauthorized().body(msg);
+    }
+    let code = match &request.code {
+        Some(code) => code,
+        None => return HttpResponse::BadRequest().body("The callback has no code"),
+    };
+
+    let result = provider
+        .exchange_code(code, &login_state.code_verifier, &login_state.nonce)
+        .await;
+    let claims = match result {
+        Ok(claims) => claims,
+        Err(err) => {
+            warn!("oidc login failed: {}", err);
+            return HttpResponse::Unauthorized()
+                .body("Could not verify the login with the identity provider");
+        }
+    };
+
+    let issuer = provider.issuer();
+    let result = OidcIdentity::find_user_id(issuer, &claims.sub, db_pool.get_ref()).await;
+    let user_id = match (result, login_state.link_user_id) {
+        (Ok(Some(user_id)), Some(link_user_id)) if user_id != link_user_id => {
+            return HttpResponse::Conflict()
+                .body("This identity is already linked to another account");
+        }
+        (Ok(Some(user_id)), _) => user_id,
+        (Ok(None), Some(link_user_id)) => {
+            let result =
+                OidcIdentity::link(issuer, &claims.sub, link_user_id, db_pool.get_ref()).await;
+            if let Err(err) = result {
+                error!("error linking oidc identity: {}", err);
+                return HttpResponse::InternalServerError()
+                    .body("Error trying to link the identity");
+            }
+            link_user_id
+        }
+        (Ok(None), None) if !provider.settings.auto_provision => {
+            return HttpResponse::Forbidden().body("No account is linked to this identity");
+        }
+        (Ok(None), None) => {
+            let result = provision_user(&claims, issuer, &passwords, db_pool.get_ref()).await;
+            match result {
+                Ok(user_id) => user_id,
+                Err(err) => {
+                    error!("error provisioning user for oidc login: {}", err);
+                    return HttpResponse::InternalServerError()
+                        .body("Error trying to create an account for the identity");
+                }
+            }
+        }
+        (Err(err), _) => {
+            error!("error finding oidc identity: {}", err);
+            return HttpResponse::InternalServerError().body("Error trying to finish OIDC login");
+        }
+    };
+
+    let user = match User::find_by_id(user_id, db_pool.get_ref()).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return HttpResponse::Unauthorized().body("The linked account no longer exists")
+        }
+        Err(err) => {
+            error!("error finding user: {}", err);
+            return HttpResponse::InternalServerError().body("Error trying to finish OIDC login");
+        }
+    };
+    let user = match auth::ensure_not_suspended(user) {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    // the provider stands in for the password, 2FA is still asked for at /users/login/2fa
+    match TotpCredential::find_by_user(user.id, db_pool.get_ref()).await {
+        Ok(Some(credential)) if credential.enabled_at.is_some() => {
+            let ttl = tokens.login_challenge_ttl();
+            let result = LoginChallenge::create(user.id, ttl, db_pool.get_ref()).await;
+            return match result {
+                Ok((challenge, value)) => HttpResponse::Accepted().json(LoginChallengeResponse {
+                    challenge: value,
+                    expires_at: challenge.expires_at,
+                }),
+                Err(err) => {
+                    error!("error creating login challenge: {}", err);
+                    HttpResponse::InternalServerError()
+                        .body("Error trying to create login challenge")
+                }
+            };
+        }
+        Ok(_) => {}
+        Err(err) => {
+            error!("error fetching totp credential: {}", err);
+            return HttpResponse::InternalServerError()
+                .body("Error trying to read two-factor settings from database");
+        }
+    }
+
+    let token = Token::create(user.id, db_pool.get_ref()).await;
+    match token {
+        Ok(token) => {
+            audit::record(
+                &req,
+                AuditAction::Login,
+                Some(user.id),
+                None,
+                json!({ "method": "oidc", "issuer": issuer, "token_id": token.id }),
+                db_pool.get_ref(),
+            )
+            .await;
+            HttpResponse::Ok().json(token)
+        }
+        Err(err) => {
+            error!("error creating token: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to create new token")
+        }
+    }
+}
+
+// creates an account for a first-time subject; the random password is never shown, so the
+// account is only reachable through the provider until the user resets it
+async fn provision_user(
+    claims: &IdTokenClaims,
+    issuer: &str,
+    passwords: &Passwords,
+    pool: &PgPool,
+) -> Result<Uuid> {
+    // a verified address is only taken over when no other account uses it, so the provider
+    // can never be used to sign in to an existing local account
+    let mut email = match (&claims.email, claims.email_verified) {
+        (Some(email), Some(true)) => Some(email.clone()),
+        _ => None,
+    };
+    if let Some(address) = &email {
+        if User::find_by_email(address, pool).await?.is_some() {
+            email = None;
+        }
+    }
+
+    let base = claims
+        .preferred_username
+        .as_deref()
+        .or_else(|| {
+            claims
+                .email
+                .as_deref()
+                .and_then(|email| email.split('@').next())
+        })
+        .unwrap_or("user")
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || ['.', '_', '-'].contains(c))
+        .collect::<String>();
+    let base = if base.is_empty() {
+        "user".to_string()
+    } else {
+        base
+    };
+    let mut username = base.clone();
+    while User::find_by_username(&username, pool).await?.is_some() {
+        let suffix = base32::encode(
+            base32::Alphabet::RFC4648 { padding: false },
+            &generate_random_u8s(3),
+        )
+        .to_lowercase();
+        username = format!("{}-{}", base, suffix);
+    }
+
+    let request = UserPostRequest {
+        name: claims.name.clone().unwrap_or_else(|| username.clone()),
+        username,
+        password: base64::encode_config(generate_random_u8s(32), base64::URL_SAFE_NO_PAD),
+        email: email.clone(),
+    };
+    let user = User::create(request, passwords, pool).await?;
+    if let Some(email) = email {
+        User::verify_email(user.id, &email, pool).await?;
+    }
+
+    // a concurrent first login of the same subject may have won the race
+    if !OidcIdentity::link(issuer, &claims.sub, user.id, pool).await? {
+        User::delete(user.id, pool).await?;
+        return OidcIdentity::find_user_id(issuer, &claims.sub, pool)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("identity vanished while linking it"));
+    }
+
+    Ok(user.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oidc::test_provider::TestProvider;
+    use crate::test_support;
+    use crate::two_factor::totp;
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App, Error};
+    use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+    use serde_json::Value;
+
+    // starts a login and finishes it at the callback as the test provider's subject
+    async fn log_in<S>(app: &mut S, sub: &str) -> ServiceResponse
+    where
+        S: Service<Request = actix_http::Request, Response = ServiceResponse, Error = Error>,
+    {
+        let req = test::TestRequest::get()
+            .uri("/users/login/oidc")
+            .to_request();
+        let started: Value = test::read_response_json(app, req).await;
+        let authorization_url = started["authorization_url"].as_str().unwrap();
+        let param = |name: &str| {
+            let (_, query) = authorization_url.split_once('?').unwrap();
+            let value = query
+                .split('&')
+                .find_map(|param| param.strip_prefix(&format!("{}=", name)))
+                .unwrap();
+            percent_decode_str(value).decode_utf8().unwrap().to_string()
+        };
+
+        let code = format!("{}.{}", sub, param("nonce"));
+        let uri = format!(
+            "/users/login/oidc/callback?state={}&code={}",
+            utf8_percent_encode(&param("state"), NON_ALPHANUMERIC),
+            utf8_percent_encode(&code, NON_ALPHANUMERIC),
+        );
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        test::call_service(app, req).await
+    }
+
+    #[actix_rt::test]
+    async fn two_factor_accounts_get_a_challenge() {
+        let pool = test_support::pool().await;
+        let idp = TestProvider::start();
+        let mut app = test::init_service(
+            App::new()
+                .data(pool.clone())
+                .app_data(web::Data::new(Some(idp.provider(true))))
+                .app_data(web::Data::new(test_support::passwords()))
+                .app_data(web::Data::new(TokenConfig::default()))
+                .configure(init),
+        )
+        .await;
+
+        // the first login provisions the account and signs straight in
+        let sub = Uuid::new_v4().to_string();
+        let res = log_in(&mut app, &sub).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let token: Value = test::read_body_json(res).await;
+        let user_id = OidcIdentity::find_user_id(&idp.issuer(), &sub, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(token["user_id"], user_id.to_string());
+
+        TotpCredential::create_pending(user_id, &totp::generate_secret(), &pool)
+            .await
+            .unwrap();
+        TotpCredential::enable(user_id, 0, &pool).await.unwrap();
+
+        // with 2FA on, the provider's word only earns a challenge
+        let res = log_in(&mut app, &sub).await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let challenge: Value = test::read_body_json(res).await;
+        assert!(challenge["challenge"].is_string());
+        assert!(challenge.get("value").is_none());
+
+        User::delete(user_id, &pool).await.unwrap();
+    }
+}
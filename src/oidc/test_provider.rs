@@ -0,0 +1,123 @@
+// a stand-in identity provider for the tests: discovery, a token endpoint and a JWKS, signing
+// with the throwaway key in test_key.pem
+use crate::config::OidcConfig;
+use crate::oidc::OidcProvider;
+use actix_web::{test, web, App, HttpRequest, HttpResponse};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+pub const CLIENT_ID: &str = "test-client";
+pub const KEY_ID: &str = "test-key";
+
+const PRIVATE_KEY: &str = include_str!("test_key.pem");
+// the public half of PRIVATE_KEY, as JWK components
+const MODULUS: &str = "2F0M4jNo6lPqZurpIpCP4tmkBykJwh9p0weJJHQj6kgjm6A_1hQdmjCU9BmD43E8vOcHx9Dyhm4sgYc1scPql2hd4Z8V8YkGEHvsTlm87e0YihdObN2D-TG6_Qh30YprXRKMg_HDXp0R6mpz9U16lmyNzoFbaKNIZlUwbyIPrs-PIV-4nFA4QHJEGr8qcORitutfGUB7p3CJRrYTTb46sae2t1IVGRcOGV9yRoxp2o_V60J2Xcaf9uxEaDZ_8nusmxy7int-gSkxp7umjAfcpafK2qrdcY2hfxesA_XQtZ6rIa6H3wxN75sYEfgvAndYfz5j3H9jXZkhx1UyHm_iXQ";
+const EXPONENT: &str = "AQAB";
+
+pub struct TestProvider {
+    server: test::TestServer,
+    jwks_fetches: Arc<AtomicUsize>,
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    code: String,
+}
+
+impl TestProvider {
+    pub fn start() -> TestProvider {
+        let jwks_fetches = Arc::new(AtomicUsize::new(0));
+        let counter = jwks_fetches.clone();
+        let server = test::start(move || {
+            App::new()
+                .data(counter.clone())
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(discovery),
+                )
+                .route("/token", web::post().to(token))
+                .route("/jwks", web::get().to(jwks))
+        });
+        TestProvider {
+            server,
+            jwks_fetches,
+        }
+    }
+
+    pub fn issuer(&self) -> String {
+        format!("http://127.0.0.1:{}", self.server.addr().port())
+    }
+
+    pub fn jwks_fetches(&self) -> usize {
+        self.jwks_fetches.load(Ordering::SeqCst)
+    }
+
+    pub fn provider(&self, auto_provision: bool) -> OidcProvider {
+        let config = OidcConfig {
+            issuer_url: Some(self.issuer()),
+            client_id: Some(CLIENT_ID.to_string()),
+            client_secret: None,
+            redirect_url: Some("http://localhost:8080/users/login/oidc/callback".to_string()),
+            auto_provision,
+        };
+        OidcProvider::from_config(&config).unwrap()
+    }
+}
+
+pub fn sign_id_token(issuer: &str, sub: &str, nonce: &str, kid: &str) -> String {
+    let now = chrono::Utc::now().timestamp();
+    let claims = json!({
+        "iss": issuer,
+        "aud": CLIENT_ID,
+        "sub": sub,
+        "nonce": nonce,
+        "iat": now,
+        "exp": now + 300,
+        "preferred_username": "oidc-user",
+    });
+    let header = Header {
+        kid: Some(kid.to_string()),
+        ..Header::new(Algorithm::RS256)
+    };
+    let key = EncodingKey::from_rsa_pem(PRIVATE_KEY.as_bytes()).unwrap();
+    encode(&header, &claims, &key).unwrap()
+}
+
+async fn discovery(req: HttpRequest) -> HttpResponse {
+    let issuer = issuer_of(&req);
+    HttpResponse::Ok().json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "jwks_uri": format!("{}/jwks", issuer),
+    }))
+}
+
+// there is no login page, the code handed in is "<subject>.<nonce>"
+async fn token(req: HttpRequest, form: web::Form<TokenForm>) -> HttpResponse {
+    let (sub, nonce) = match form.code.split_once('.') {
+        Some(parts) => parts,
+        None => return HttpResponse::BadRequest().finish(),
+    };
+    let id_token = sign_id_token(&issuer_of(&req), sub, nonce, KEY_ID);
+    HttpResponse::Ok().json(json!({ "id_token": id_token }))
+}
+
+async fn jwks(jwks_fetches: web::Data<Arc<AtomicUsize>>) -> HttpResponse {
+    jwks_fetches.fetch_add(1, Ordering::SeqCst);
+    HttpResponse::Ok().json(json!({
+        "keys": [{
+            "kty": "RSA",
+            "kid": KEY_ID,
+            "n": MODULUS,
+            "e": EXPONENT,
+        }],
+    }))
+}
+
+fn issuer_of(req: &HttpRequest) -> String {
+    format!("http://{}", req.connection_info().host())
+}
@@ -0,0 +1,313 @@
+use crate::config::{HasherKind, PasswordConfig};
+use crate::token::generate_random_u8s;
+use actix_web::error::BlockingError;
+use actix_web::web;
+use anyhow::{anyhow, Result};
+use argon2::password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+mod policy;
+
+pub use policy::*;
+
+// one password hashing scheme; Passwords decides which one hashes and which ones only verify
+pub trait PasswordHasher: Send + Sync {
+    // whether the stored hash was produced by this scheme
+    fn recognizes(&self, hash: &str) -> bool;
+    fn hash(&self, password: &str) -> Result<String>;
+    fn verify(&self, password: &str, hash: &str) -> bool;
+    // whether a hash of this scheme was made with weaker settings than the current ones
+    fn is_outdated(&self, hash: &str) -> bool;
+}
+
+pub struct Argon2idHasher {
+    params: Params,
+}
+
+impl Argon2idHasher {
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Argon2idHasher> {
+        let params = Params::new(memory_kib, iterations, parallelism, None)
+            .map_err(|err| anyhow!("invalid argon2 parameters: {}", err))?;
+        Ok(Argon2idHasher { params })
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+}
+
+impl PasswordHasher for Argon2idHasher {
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$argon2")
+    }
+
+    fn hash(&self, password: &str) -> Result<String> {
+        let salt = SaltString::b64_encode(&generate_random_u8s(16))
+            .map_err(|err| anyhow!("error encoding salt: {}", err))?;
+        let hash = self
+            .argon2()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|err| anyhow!("error hashing password: {}", err))?;
+        Ok(hash.to_string())
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        match PasswordHash::new(hash) {
+            Ok(parsed) => self
+                .argon2()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn is_outdated(&self, hash: &str) -> bool {
+        let parsed = match PasswordHash::new(hash) {
+            Ok(parsed) => parsed,
+            Err(_) => return true,
+        };
+        if parsed.algorithm.as_str() != "argon2id" {
+            return true;
+        }
+        match Params::try_from(&parsed) {
+            Ok(params) => {
+                params.m_cost() < self.params.m_cost()
+                    || params.t_cost() < self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+pub struct BcryptHasher {
+    cost: u32,
+}
+
+impl BcryptHasher {
+    pub fn new(cost: u32) -> BcryptHasher {
+        BcryptHasher { cost }
+    }
+}
+
+impl PasswordHasher for BcryptHasher {
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$2")
+    }
+
+    fn hash(&self, password: &str) -> Result<String> {
+        Ok(bcrypt::hash(password, self.cost)?)
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+
+    fn is_outdated(&self, hash: &str) -> bool {
+        // bcrypt hashes look like $2b$12$..., the cost sits between the second and third `$`
+        match hash.split('$').nth(2).map(|cost| cost.parse::<u32>()) {
+            Some(Ok(cost)) => cost < self.cost,
+            _ => true,
+        }
+    }
+}
+
+pub struct Verification {
+    pub valid: bool,
+    // the password was right but the hash should be replaced with one from the current hasher
+    pub needs_rehash: bool,
+}
+
+pub struct Passwords {
+    current: Arc<dyn PasswordHasher>,
+    legacy: Vec<Arc<dyn PasswordHasher>>,
+    // a hash of a random password, verified against when there is no real hash to check,
+    // so that path costs as much as a wrong password does
+    dummy_hash: String,
+    // hashing runs on actix's blocking thread pool; capping how much of it may run at once
+    // keeps a login storm from tying up every thread other blocking work needs
+    permits: Semaphore,
+    policy: PasswordPolicy,
+}
+
+impl Passwords {
+    pub fn new(
+        current: Arc<dyn PasswordHasher>,
+        legacy: Vec<Arc<dyn PasswordHasher>>,
+        max_concurrent: usize,
+        policy: PasswordPolicy,
+    ) -> Result<Passwords> {
+        let dummy_password = base64::encode(generate_random_u8s(16));
+        let dummy_hash = current.hash(&dummy_password)?;
+        Ok(Passwords {
+            current,
+            legacy,
+            dummy_hash,
+            permits: Semaphore::new(max_concurrent),
+            policy,
+        })
+    }
+
+    // the configured hasher makes new hashes, the other one is still used to verify
+    // hashes it made
+    pub fn from_config(config: &PasswordConfig) -> Result<Passwords> {
+        let argon2id = Arc::new(Argon2idHasher::new(
+            config.argon2_memory_kib,
+            config.argon2_iterations,
+            config.argon2_parallelism,
+        )?);
+        let bcrypt = Arc::new(BcryptHasher::new(config.bcrypt_cost));
+        let max_concurrent = config.hashing_concurrency;
+        let policy = PasswordPolicy {
+            min_length: config.min_length,
+        };
+
+        match config.hasher {
+            HasherKind::Bcrypt => Passwords::new(bcrypt, vec![argon2id], max_concurrent, policy),
+            HasherKind::Argon2id => Passwords::new(argon2id, vec![bcrypt], max_concurrent, policy),
+        }
+    }
+
+    pub fn check_policy(
+        &self,
+        password: &str,
+        username: &str,
+        name: &str,
+    ) -> Result<(), PolicyError> {
+        self.policy.check(password, username, name)
+    }
+
+    pub async fn hash(&self, password: &str) -> Result<String> {
+        let hasher = self.current.clone();
+        let password = password.to_string();
+        self.run_blocking(move || hasher.hash(&password)).await
+    }
+
+    pub async fn verify(&self, password: &str, hash: &str) -> Result<Verification> {
+        let (hasher, is_current) = if self.current.recognizes(hash) {
+            (self.current.clone(), true)
+        } else {
+            match self.legacy.iter().find(|hasher| hasher.recognizes(hash)) {
+                Some(hasher) => (hasher.clone(), false),
+                None => {
+                    return Ok(Verification {
+                        valid: false,
+                        needs_rehash: false,
+                    })
+                }
+            }
+        };
+
+        let password = password.to_string();
+        let hash = hash.to_string();
+        self.run_blocking(move || {
+            let valid = hasher.verify(&password, &hash);
+            Ok(Verification {
+                valid,
+                needs_rehash: valid && (!is_current || hasher.is_outdated(&hash)),
+            })
+        })
+        .await
+    }
+
+    // burns the same time as verifying a real password, for when there is nothing to verify
+    pub async fn verify_dummy(&self, password: &str) -> Result<()> {
+        let hasher = self.current.clone();
+        let password = password.to_string();
+        let dummy_hash = self.dummy_hash.clone();
+        self.run_blocking(move || {
+            hasher.verify(&password, &dummy_hash);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn run_blocking<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self.permits.acquire().await;
+        match web::block(f).await {
+            Ok(value) => Ok(value),
+            Err(BlockingError::Error(err)) => Err(err),
+            Err(BlockingError::Canceled) => Err(anyhow!("password hashing was canceled")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    const ROUNDS: usize = 7;
+    // how far apart the two may be, relative to the slower one
+    const TOLERANCE: f64 = 0.25;
+
+    #[actix_rt::test]
+    async fn dummy_verify_takes_as_long_as_a_real_one() {
+        // cheaper than the defaults so the test stays quick, but still dominated by hashing
+        let config = PasswordConfig {
+            argon2_memory_kib: 4096,
+            hashing_concurrency: 1,
+            ..PasswordConfig::default()
+        };
+        let passwords = Passwords::from_config(&config).unwrap();
+        let hash = passwords.hash("wobbly-lantern-42").await.unwrap();
+
+        // the fastest round of each, other tests running alongside can only ever slow one down
+        let mut real = Duration::MAX;
+        let mut dummy = Duration::MAX;
+        // interleaved, so a slow patch on the machine hits both sides alike
+        for _ in 0..ROUNDS {
+            let started = Instant::now();
+            let verification = passwords.verify("wrong-password", &hash).await.unwrap();
+            real = real.min(started.elapsed());
+            assert!(!verification.valid);
+
+            let started = Instant::now();
+            passwords.verify_dummy("wrong-password").await.unwrap();
+            dummy = dummy.min(started.elapsed());
+        }
+
+        let difference = real.max(dummy) - real.min(dummy);
+        assert!(
+            difference.as_secs_f64() <= real.max(dummy).as_secs_f64() * TOLERANCE,
+            "verify took {:?} but verify_dummy took {:?}",
+            real,
+            dummy
+        );
+    }
+
+    #[test]
+    fn weaker_argon2_hashes_are_outdated() {
+        let current = Argon2idHasher::new(2048, 2, 1).unwrap();
+        let hash = current.hash("wobbly-lantern-42").unwrap();
+        assert!(!current.is_outdated(&hash));
+
+        let less_memory = Argon2idHasher::new(1024, 2, 1).unwrap();
+        assert!(current.is_outdated(&less_memory.hash("wobbly-lantern-42").unwrap()));
+        let fewer_iterations = Argon2idHasher::new(2048, 1, 1).unwrap();
+        assert!(current.is_outdated(&fewer_iterations.hash("wobbly-lantern-42").unwrap()));
+        // a bcrypt hash is no argon2id hash at all
+        let bcrypt = BcryptHasher::new(4).hash("wobbly-lantern-42").unwrap();
+        assert!(current.is_outdated(&bcrypt));
+    }
+
+    #[test]
+    fn weaker_bcrypt_hashes_are_outdated() {
+        let current = BcryptHasher::new(5);
+        let hash = current.hash("wobbly-lantern-42").unwrap();
+        assert!(!current.is_outdated(&hash));
+
+        let cheaper = BcryptHasher::new(4).hash("wobbly-lantern-42").unwrap();
+        assert!(current.is_outdated(&cheaper));
+        // a stronger hash is left alone, there is nothing to gain from rehashing it
+        let stronger = BcryptHasher::new(6).hash("wobbly-lantern-42").unwrap();
+        assert!(!current.is_outdated(&stronger));
+    }
+}
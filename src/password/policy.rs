@@ -0,0 +1,168 @@
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::fmt;
+
+// parts of a username or name shorter than this are too common to be worth rejecting
+const MIN_IDENTITY_PART_LEN: usize = 3;
+
+#[derive(Serialize, Debug)]
+pub struct PolicyViolation {
+    pub code: &'static str,
+    pub message: String,
+}
+
+// every rule a password broke, returned as the body of a 422
+#[derive(Serialize, Debug)]
+pub struct PolicyError {
+    pub errors: Vec<PolicyViolation>,
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let codes = self
+            .errors
+            .iter()
+            .map(|violation| violation.code)
+            .collect::<Vec<&str>>();
+        write!(f, "password violates policy: {}", codes.join(", "))
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+pub struct PasswordPolicy {
+    pub min_length: usize,
+}
+
+impl PasswordPolicy {
+    pub fn check(&self, password: &str, username: &str, name: &str) -> Result<(), PolicyError> {
+        let mut errors = Vec::new();
+
+        if password.chars().count() < self.min_length {
+            errors.push(PolicyViolation {
+                code: "too_short",
+                message: format!(
+                    "Password must be at least {} characters long",
+                    self.min_length
+                ),
+            });
+        }
+
+        let lowercase = password.to_lowercase();
+        let contains_identity = std::iter::once(username)
+            .chain(name.split_whitespace())
+            .map(|part| part.to_lowercase())
+            .filter(|part| part.chars().count() >= MIN_IDENTITY_PART_LEN)
+            .any(|part| lowercase.contains(&part));
+        if contains_identity {
+            errors.push(PolicyViolation {
+                code: "contains_identity",
+                message: "Password must not contain your username or name".to_string(),
+            });
+        }
+
+        if is_breached(password) || is_breached(&lowercase) {
+            errors.push(PolicyViolation {
+                code: "breached",
+                message: "Password is too common or has appeared in a data breach".to_string(),
+            });
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(PolicyError { errors })
+        }
+    }
+}
+
+// the list is stored the way k-anonymity range APIs serve it: the uppercase hex SHA-1 of every
+// password, split into one file per first hex digit that holds the remaining 39 digits
+fn is_breached(password: &str) -> bool {
+    let digest = Sha1::digest(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<String>();
+    let (prefix, suffix) = digest.split_at(1);
+    suffixes(prefix).lines().any(|line| line == suffix)
+}
+
+fn suffixes(prefix: &str) -> &'static str {
+    match prefix {
+        "0" => include_str!("breached/0.txt"),
+        "1" => include_str!("breached/1.txt"),
+        "2" => include_str!("breached/2.txt"),
+        "3" => include_str!("breached/3.txt"),
+        "4" => include_str!("breached/4.txt"),
+        "5" => include_str!("breached/5.txt"),
+        "6" => include_str!("breached/6.txt"),
+        "7" => include_str!("breached/7.txt"),
+        "8" => include_str!("breached/8.txt"),
+        "9" => include_str!("breached/9.txt"),
+        "A" => include_str!("breached/A.txt"),
+        "B" => include_str!("breached/B.txt"),
+        "C" => include_str!("breached/C.txt"),
+        "D" => include_str!("breached/D.txt"),
+        "E" => include_str!("breached/E.txt"),
+        "F" => include_str!("breached/F.txt"),
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(result: Result<(), PolicyError>) -> Vec<&'static str> {
+        match result {
+            Ok(()) => Vec::new(),
+            Err(err) => err.errors.iter().map(|violation| violation.code).collect(),
+        }
+    }
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy { min_length: 8 }
+    }
+
+    #[test]
+    fn rejects_short_passwords() {
+        assert_eq!(
+            codes(policy().check("w0bbly!", "alice", "Alice Smith")),
+            vec!["too_short"]
+        );
+    }
+
+    #[test]
+    fn rejects_passwords_containing_the_username_or_name() {
+        let policy = policy();
+        assert_eq!(
+            codes(policy.check("xx-Alice-2024", "alice", "Jane Doe")),
+            vec!["contains_identity"]
+        );
+        assert_eq!(
+            codes(policy.check("lantern-SMITH-9", "jdoe", "Alice Smith")),
+            vec!["contains_identity"]
+        );
+    }
+
+    #[test]
+    fn rejects_breached_passwords_in_any_case() {
+        let policy = policy();
+        assert_eq!(
+            codes(policy.check("password", "alice", "Alice Smith")),
+            vec!["breached"]
+        );
+        assert_eq!(
+            codes(policy.check("PassWord", "alice", "Alice Smith")),
+            vec!["breached"]
+        );
+    }
+
+    #[test]
+    fn accepts_a_long_unrelated_password() {
+        assert_eq!(
+            codes(policy().check("wobbly-lantern-42", "alice", "Alice Smith")),
+            Vec::<&str>::new()
+        );
+    }
+}
@@ -2,6 +2,7 @@ use actix_web::{Error, HttpRequest, HttpResponse, Responder};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use sqlx::{Done, FromRow, PgPool};
+use tracing::instrument;
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize)]
@@ -28,11 +29,15 @@ impl Responder for Post {
 }
 
 impl Post {
+    // posts by suspended users stay hidden until they are reinstated
+    #[instrument(skip_all)]
     pub async fn find_all(pool: &PgPool) -> Result<Vec<Post>> {
         let posts = sqlx::query!(
             r#"
-            SELECT id, title, body, user_id
-            FROM posts
+            SELECT posts.id, posts.title, posts.body, posts.user_id
+            FROM posts INNER JOIN users
+            ON posts.user_id = users.id
+            WHERE users.disabled_at IS NULL
             "#
         )
         .fetch_all(pool)
@@ -49,6 +54,38 @@ impl Post {
         Ok(posts)
     }
 
+    // same as find_all, minus posts by users the viewer has blocked or muted
+    #[instrument(skip_all)]
+    pub async fn find_all_for_viewer(viewer_id: Uuid, pool: &PgPool) -> Result<Vec<Post>> {
+        let posts = sqlx::query!(
+            r#"
+            SELECT posts.id, posts.title, posts.body, posts.user_id
+            FROM posts INNER JOIN users
+            ON posts.user_id = users.id
+            WHERE users.disabled_at IS NULL
+            AND posts.user_id NOT IN (
+                SELECT blocked_id FROM blocks WHERE blocker_id = $1
+                UNION
+                SELECT muted_id FROM mutes WHERE muter_id = $1
+            )
+            "#,
+            viewer_id,
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|rec| Post {
+            id: rec.id,
+            title: rec.title,
+            body: rec.body,
+            user_id: rec.user_id,
+        })
+        .collect();
+
+        Ok(posts)
+    }
+
+    #[instrument(skip_all)]
     pub async fn find_by_id(id: Uuid, pool: &PgPool) -> Result<Option<Post>> {
         let rec = sqlx::query!(
             r#"
@@ -69,6 +106,7 @@ impl Post {
         }))
     }
 
+    #[instrument(skip_all)]
     pub async fn create(post: PostRequest, user_id: Uuid, pool: &PgPool) -> Result<Post> {
         let post_id = Uuid::new_v4();
 
@@ -108,6 +146,7 @@ impl Post {
         })
     }
 
+    #[instrument(skip_all)]
     pub async fn update(
         id: Uuid,
         post: PostRequest,
@@ -157,6 +196,7 @@ impl Post {
         Ok(Some(post))
     }
 
+    #[instrument(skip_all)]
     pub async fn delete(id: Uuid, user_id: Uuid, pool: &PgPool) -> Result<u64> {
         let mut tx = pool.begin().await?;
 
@@ -177,6 +217,28 @@ impl Post {
         Ok(n_deleted)
     }
 
+    // unlike delete, this doesn't check ownership; callers must authorize first
+    #[instrument(skip_all)]
+    pub async fn delete_any(id: Uuid, pool: &PgPool) -> Result<u64> {
+        let mut tx = pool.begin().await?;
+
+        let n_deleted = sqlx::query!(
+            r#"
+            DELETE FROM posts
+            WHERE id = $1
+            "#,
+            id,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(n_deleted)
+    }
+
+    #[instrument(skip_all)]
     pub async fn find_by_user(user_id: Uuid, pool: &PgPool) -> Result<Vec<Post>> {
         let posts = sqlx::query!(
             r#"
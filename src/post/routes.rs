@@ -1,9 +1,12 @@
-use crate::auth;
+use crate::audit::{self, AuditAction, AuditTarget};
+use crate::auth::{self, Scope};
+use crate::email_verification::EmailSettings;
 use crate::post::{Post, PostRequest};
 use crate::user::{User, UserPublic};
-use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, Responder};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use log::error;
+use serde_json::json;
 use sqlx::PgPool;
 use uuid::Uuid;
 
@@ -17,8 +20,22 @@ pub fn init(cfg: &mut web::ServiceConfig) {
 }
 
 #[get("/posts")]
-async fn find_all(db_pool: web::Data<PgPool>) -> impl Responder {
-    let result = Post::find_all(db_pool.get_ref()).await;
+async fn find_all(credentials: Option<BearerAuth>, db_pool: web::Data<PgPool>) -> impl Responder {
+    // anonymous visitors get every post, signed in users don't see the people they blocked or muted
+    let result = match credentials {
+        Some(credentials) => {
+            let result =
+                auth::validate_bearer_auth(credentials, Scope::PostsRead, db_pool.get_ref()).await;
+            let user = match result {
+                Ok(user) => user,
+                Err(err) => {
+                    return HttpResponse::from_error(err);
+                }
+            };
+            Post::find_all_for_viewer(user.id, db_pool.get_ref()).await
+        }
+        None => Post::find_all(db_pool.get_ref()).await,
+    };
     match result {
         Ok(posts) => HttpResponse::Ok().json(posts),
         Err(err) => {
@@ -46,14 +63,21 @@ async fn create(
     credentials: BearerAuth,
     post: web::Json<PostRequest>,
     db_pool: web::Data<PgPool>,
+    email_settings: web::Data<EmailSettings>,
 ) -> impl Responder {
-    let user = match auth::validate_bearer_auth(credentials, db_pool.get_ref()).await {
+    let result =
+        auth::validate_bearer_auth(credentials, Scope::PostsWrite, db_pool.get_ref()).await;
+    let user = match result {
         Ok(user) => user,
         Err(err) => {
             return HttpResponse::from_error(err);
         }
     };
 
+    if email_settings.require_verified_to_post && user.email_verified_at.is_none() {
+        return HttpResponse::Forbidden().body("Verify your email address before posting");
+    }
+
     let result = Post::create(post.into_inner(), user.id, db_pool.get_ref()).await;
     match result {
         Ok(post) => HttpResponse::Ok().json(post),
@@ -66,12 +90,15 @@ async fn create(
 
 #[put("/posts/{id}")]
 async fn update(
+    req: HttpRequest,
     credentials: BearerAuth,
     id: web::Path<Uuid>,
     post: web::Json<PostRequest>,
     db_pool: web::Data<PgPool>,
 ) -> impl Responder {
-    let user = match auth::validate_bearer_auth(credentials, db_pool.get_ref()).await {
+    let result =
+        auth::validate_bearer_auth(credentials, Scope::PostsWrite, db_pool.get_ref()).await;
+    let user = match result {
         Ok(user) => user,
         Err(err) => {
             return HttpResponse::from_error(err);
@@ -87,7 +114,18 @@ async fn update(
     .await;
 
     match result {
-        Ok(Some(post)) => HttpResponse::Ok().json(post),
+        Ok(Some(post)) => {
+            audit::record(
+                &req,
+                AuditAction::PostUpdated,
+                Some(user.id),
+                Some(AuditTarget::Post(post.id)),
+                json!({}),
+                db_pool.get_ref(),
+            )
+            .await;
+            HttpResponse::Ok().json(post)
+        }
         Ok(None) => HttpResponse::NotFound().body("Post not found"),
         Err(err) => {
             error!("error updating post: {}", err);
@@ -98,21 +136,34 @@ async fn update(
 
 #[delete("/posts/{id}")]
 async fn delete(
+    req: HttpRequest,
     credentials: BearerAuth,
     id: web::Path<Uuid>,
     db_pool: web::Data<PgPool>,
 ) -> impl Responder {
-    let user = match auth::validate_bearer_auth(credentials, db_pool.get_ref()).await {
+    let result =
+        auth::validate_bearer_auth(credentials, Scope::PostsWrite, db_pool.get_ref()).await;
+    let user = match result {
         Ok(user) => user,
         Err(err) => {
             return HttpResponse::from_error(err);
         }
     };
 
-    let result = Post::delete(id.into_inner(), user.id, db_pool.get_ref()).await;
+    let id = id.into_inner();
+    let result = Post::delete(id, user.id, db_pool.get_ref()).await;
     match result {
         Ok(rows_deleted) => {
             if rows_deleted > 0 {
+                audit::record(
+                    &req,
+                    AuditAction::PostDeleted,
+                    Some(user.id),
+                    Some(AuditTarget::Post(id)),
+                    json!({}),
+                    db_pool.get_ref(),
+                )
+                .await;
                 let msg = format!("Successfully deleted {} record(s)", rows_deleted);
                 HttpResponse::Ok().body(msg)
             } else {
@@ -0,0 +1,104 @@
+use anyhow::Result;
+use serde::Serialize;
+use sqlx::{Done, FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(Serialize, FromRow)]
+pub struct Block {
+    pub blocker_id: Uuid,
+    pub blocked_id: Uuid,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Mute {
+    pub muter_id: Uuid,
+    pub muted_id: Uuid,
+}
+
+impl Block {
+    pub async fn create(blocker_id: Uuid, blocked_id: Uuid, pool: &PgPool) -> Result<Block> {
+        let mut tx = pool.begin().await?;
+
+        // blocking someone twice is not an error, the relation just stays as is
+        sqlx::query!(
+            r#"
+            INSERT INTO blocks (blocker_id, blocked_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+            blocker_id,
+            blocked_id,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(Block {
+            blocker_id,
+            blocked_id,
+        })
+    }
+
+    pub async fn delete(blocker_id: Uuid, blocked_id: Uuid, pool: &PgPool) -> Result<u64> {
+        let mut tx = pool.begin().await?;
+
+        let n_deleted = sqlx::query!(
+            r#"
+            DELETE FROM blocks
+            WHERE blocker_id = $1 AND blocked_id = $2
+            "#,
+            blocker_id,
+            blocked_id,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(n_deleted)
+    }
+}
+
+impl Mute {
+    pub async fn create(muter_id: Uuid, muted_id: Uuid, pool: &PgPool) -> Result<Mute> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO mutes (muter_id, muted_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+            muter_id,
+            muted_id,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(Mute { muter_id, muted_id })
+    }
+
+    pub async fn delete(muter_id: Uuid, muted_id: Uuid, pool: &PgPool) -> Result<u64> {
+        let mut tx = pool.begin().await?;
+
+        let n_deleted = sqlx::query!(
+            r#"
+            DELETE FROM mutes
+            WHERE muter_id = $1 AND muted_id = $2
+            "#,
+            muter_id,
+            muted_id,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(n_deleted)
+    }
+}
@@ -0,0 +1,159 @@
+use crate::auth::{self, Scope};
+use crate::relation::{Block, Mute};
+use crate::user::User;
+use actix_web::{delete, post, web, HttpResponse, Responder};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use log::error;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(block)
+        .service(unblock)
+        .service(mute)
+        .service(unmute);
+}
+
+#[post("/users/{id}/block")]
+async fn block(
+    credentials: BearerAuth,
+    id: web::Path<Uuid>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result =
+        auth::validate_bearer_auth(credentials, Scope::UsersWrite, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    let target_id = id.into_inner();
+    if target_id == user.id {
+        return HttpResponse::BadRequest().body("You cannot block yourself");
+    }
+
+    match User::find_by_id(target_id, db_pool.get_ref()).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().body("User not found"),
+        Err(err) => {
+            error!("error fetching user: {}", err);
+            return HttpResponse::InternalServerError()
+                .body("Error trying to read user from database");
+        }
+    }
+
+    let result = Block::create(user.id, target_id, db_pool.get_ref()).await;
+    match result {
+        Ok(block) => HttpResponse::Ok().json(block),
+        Err(err) => {
+            error!("error blocking user: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to block user")
+        }
+    }
+}
+
+#[delete("/users/{id}/block")]
+async fn unblock(
+    credentials: BearerAuth,
+    id: web::Path<Uuid>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result =
+        auth::validate_bearer_auth(credentials, Scope::UsersWrite, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    let result = Block::delete(user.id, id.into_inner(), db_pool.get_ref()).await;
+    match result {
+        Ok(rows_deleted) => {
+            if rows_deleted > 0 {
+                let msg = format!("Successfully deleted {} record(s)", rows_deleted);
+                HttpResponse::Ok().body(msg)
+            } else {
+                HttpResponse::NotFound().body("Block not found")
+            }
+        }
+        Err(err) => {
+            error!("error unblocking user: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to unblock user")
+        }
+    }
+}
+
+#[post("/users/{id}/mute")]
+async fn mute(
+    credentials: BearerAuth,
+    id: web::Path<Uuid>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result =
+        auth::validate_bearer_auth(credentials, Scope::UsersWrite, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    let target_id = id.into_inner();
+    if target_id == user.id {
+        return HttpResponse::BadRequest().body("You cannot mute yourself");
+    }
+
+    match User::find_by_id(target_id, db_pool.get_ref()).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().body("User not found"),
+        Err(err) => {
+            error!("error fetching user: {}", err);
+            return HttpResponse::InternalServerError()
+                .body("Error trying to read user from database");
+        }
+    }
+
+    let result = Mute::create(user.id, target_id, db_pool.get_ref()).await;
+    match result {
+        Ok(mute) => HttpResponse::Ok().json(mute),
+        Err(err) => {
+            error!("error muting user: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to mute user")
+        }
+    }
+}
+
+#[delete("/users/{id}/mute")]
+async fn unmute(
+    credentials: BearerAuth,
+    id: web::Path<Uuid>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result =
+        auth::validate_bearer_auth(credentials, Scope::UsersWrite, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    let result = Mute::delete(user.id, id.into_inner(), db_pool.get_ref()).await;
+    match result {
+        Ok(rows_deleted) => {
+            if rows_deleted > 0 {
+                let msg = format!("Successfully deleted {} record(s)", rows_deleted);
+                HttpResponse::Ok().body(msg)
+            } else {
+                HttpResponse::NotFound().body("Mute not found")
+            }
+        }
+        Err(err) => {
+            error!("error unmuting user: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to unmute user")
+        }
+    }
+}
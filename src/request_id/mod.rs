@@ -0,0 +1,133 @@
+use actix_web::dev::{
+    Body, MessageBody, ResponseBody, Service, ServiceRequest, ServiceResponse, Transform,
+};
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use actix_web::web::BytesMut;
+use actix_web::{Error, HttpMessage};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::{pin_mut, StreamExt};
+use serde_json::Value;
+use std::task::{Context, Poll};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// longer ones are replaced rather than trusted, they end up in every log line
+const MAX_LENGTH: usize = 128;
+
+// the id of the request being handled, in the request extensions for whoever wants it
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    fn from_request(req: &ServiceRequest) -> RequestId {
+        let id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_valid(id));
+        match id {
+            Some(id) => RequestId(id.to_string()),
+            None => RequestId(Uuid::new_v4().to_string()),
+        }
+    }
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LENGTH && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+// takes the caller's X-Request-Id or makes one up, and hands it back on the response; wrapped
+// outermost so the tracing span and every response, errors included, carry it
+pub struct RequestIdentifier;
+
+impl<S, B> Transform<S> for RequestIdentifier
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + Unpin + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdentifierMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdentifierMiddleware { service })
+    }
+}
+
+pub struct RequestIdentifierMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestIdentifierMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + Unpin + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let request_id = RequestId::from_request(&req);
+        req.extensions_mut().insert(request_id.clone());
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+
+            if !is_json_error(&res) {
+                return Ok(res.map_body(|_, body| ResponseBody::Other(Body::from_message(body))));
+            }
+            let body = res.response_mut().take_body();
+            let body = with_request_id(body, &request_id).await?;
+            Ok(res.map_body(|_, _| ResponseBody::Other(body)))
+        })
+    }
+}
+
+fn is_json_error<B>(res: &ServiceResponse<B>) -> bool {
+    let is_json = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    is_json && (res.status().is_client_error() || res.status().is_server_error())
+}
+
+// error bodies are small, so they are read whole; anything but a JSON object is left alone
+async fn with_request_id<B: MessageBody>(
+    body: ResponseBody<B>,
+    request_id: &RequestId,
+) -> Result<Body, Error> {
+    pin_mut!(body);
+    let mut bytes = BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        bytes.extend_from_slice(&chunk?);
+    }
+
+    match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(mut object)) => {
+            object.insert(
+                "request_id".to_string(),
+                Value::String(request_id.0.clone()),
+            );
+            Ok(Body::from(Value::Object(object)))
+        }
+        _ => Ok(Body::from(bytes.freeze())),
+    }
+}
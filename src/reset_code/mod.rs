@@ -0,0 +1,96 @@
+use crate::token::{generate_random_u8s, hash_secret};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(Serialize)]
+pub struct ResetCode {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl ResetCode {
+    // only the hash is stored, so the plain code is handed back to the caller to be mailed
+    pub async fn create(
+        user_id: Uuid,
+        ttl: Duration,
+        pool: &PgPool,
+    ) -> Result<(ResetCode, String)> {
+        let code = base64::encode_config(generate_random_u8s(24), base64::URL_SAFE_NO_PAD);
+        let reset_code = ResetCode {
+            id: Uuid::new_v4(),
+            user_id,
+            expires_at: Utc::now() + ttl,
+        };
+
+        let mut tx = pool.begin().await?;
+
+        // a new code replaces any code that is still pending
+        sqlx::query!(
+            r#"
+            DELETE FROM reset_codes
+            WHERE user_id = $1 AND used_at IS NULL
+            "#,
+            user_id,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO reset_codes (id, code_hash, user_id, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+            reset_code.id,
+            hash_secret(&code),
+            reset_code.user_id,
+            reset_code.expires_at,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok((reset_code, code))
+    }
+
+    // looks the code up without using it up
+    pub async fn find_user_id(code: &str, pool: &PgPool) -> Result<Option<Uuid>> {
+        let rec = sqlx::query!(
+            r#"
+            SELECT user_id
+            FROM reset_codes
+            WHERE code_hash = $1 AND used_at IS NULL AND expires_at > now()
+            "#,
+            hash_secret(code),
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(rec.map(|rec| rec.user_id))
+    }
+
+    // marks the code as used and returns its user, or None if it is unknown, used or expired
+    pub async fn redeem(code: &str, pool: &PgPool) -> Result<Option<Uuid>> {
+        let mut tx = pool.begin().await?;
+
+        let rec = sqlx::query!(
+            r#"
+            UPDATE reset_codes
+            SET used_at = now()
+            WHERE code_hash = $1 AND used_at IS NULL AND expires_at > now()
+            RETURNING user_id
+            "#,
+            hash_secret(code),
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(rec.map(|rec| rec.user_id))
+    }
+}
@@ -0,0 +1,123 @@
+use crate::request_id::RequestId;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::HeaderMap;
+use actix_web::{Error, HttpMessage};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tracing::field::Empty;
+use tracing::{info, info_span, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+// one span per request, which everything the handler logs or queries ends up under, so each
+// of those lines carries the request id; auth::ensure_not_suspended adds the user id
+pub struct RequestTracing;
+
+impl<S, B> Transform<S> for RequestTracing
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTracingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestTracingMiddleware { service })
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestTracingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        // the path without its query string, which can hold codes and tokens
+        let method = req.method().to_string();
+        let path = req.path().to_string();
+        // set by RequestIdentifier, which is wrapped around this
+        let request_id = req
+            .extensions()
+            .get::<RequestId>()
+            .map(|request_id| request_id.0.clone())
+            .unwrap_or_default();
+        let span = info_span!(
+            "request",
+            request_id = %request_id,
+            method = %method,
+            path = %path,
+            route = Empty,
+            status = Empty,
+            user_id = Empty,
+            otel.name = Empty,
+            otel.kind = "server",
+        );
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&RequestHeaders(req.headers()))
+        });
+        span.set_parent(parent);
+
+        let started = Instant::now();
+        let fut = span.in_scope(|| self.service.call(req));
+
+        Box::pin(async move {
+            let result = fut.instrument(span.clone()).await;
+
+            // routes are only resolved inside the App, so they are known after the fact
+            let (route, status) = match &result {
+                Ok(res) => (res.request().match_pattern(), res.status()),
+                Err(err) => (None, err.as_response_error().status_code()),
+            };
+            let route = route.unwrap_or_else(|| "unmatched".to_string());
+            span.record("route", &route.as_str());
+            span.record("status", &status.as_u16());
+            span.record("otel.name", &format!("{} {}", method, route).as_str());
+
+            let elapsed = started.elapsed();
+            span.in_scope(|| {
+                info!(
+                    "{} {} {} {:.3}ms",
+                    method,
+                    path,
+                    status.as_u16(),
+                    elapsed.as_secs_f64() * 1000.0
+                )
+            });
+
+            result
+        })
+    }
+}
+
+struct RequestHeaders<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for RequestHeaders<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
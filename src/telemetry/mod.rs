@@ -0,0 +1,150 @@
+// Logging and tracing. Everything goes through tracing: the log macros used across the
+// handlers are forwarded to it, model functions open a span per call, and RequestTracing
+// opens the span each request runs in. With an exporter configured the spans are also sent
+// on as OpenTelemetry traces, joined to the caller's trace through the traceparent header.
+mod middleware;
+
+pub use middleware::RequestTracing;
+
+use crate::config::{LogConfig, LogFormat, TraceExporter, TracingConfig};
+use anyhow::{anyhow, Result};
+use opentelemetry::sdk::export::trace::stdout;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use std::collections::HashMap;
+use std::fs::File;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+pub fn init(log: &LogConfig, tracing: &TracingConfig) -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&log.level));
+    let (text, json) = match log.format {
+        LogFormat::Text => (Some(fmt::layer()), None),
+        LogFormat::Json => (None, Some(fmt::layer().json().flatten_event(true))),
+    };
+    let tracer = match tracing.exporter {
+        TraceExporter::None => None,
+        TraceExporter::Otlp => Some(otlp_tracer(tracing)?),
+        TraceExporter::File => Some(file_tracer(tracing)?),
+    };
+    let otel = tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json)
+        .with(otel)
+        .try_init()?;
+    Ok(())
+}
+
+// exports whatever is still buffered, call it once the server has stopped
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+// the headers that carry the current trace on to a service we call
+pub fn trace_headers() -> HashMap<String, String> {
+    let context = Span::current().context();
+    let mut headers = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut headers));
+    headers
+}
+
+fn trace_config(config: &TracingConfig) -> trace::Config {
+    trace::config().with_resource(Resource::new(vec![KeyValue::new(
+        "service.name",
+        config.service_name.clone(),
+    )]))
+}
+
+// the simple span processor exports from a thread of its own, so it works under the
+// actix runtime, which the batch processor's tokio integration doesn't
+fn otlp_tracer(config: &TracingConfig) -> Result<trace::Tracer> {
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(&config.otlp_endpoint);
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace_config(config))
+        .install_simple()?;
+    Ok(tracer)
+}
+
+// one entry per finished span, for tests and for looking at traces without a collector
+fn file_tracer(config: &TracingConfig) -> Result<trace::Tracer> {
+    let path = config
+        .file
+        .as_ref()
+        .ok_or_else(|| anyhow!("tracing.file is required for the file exporter"))?;
+    let file =
+        File::create(path).map_err(|err| anyhow!("error creating {}: {}", path.display(), err))?;
+    let tracer = stdout::new_pipeline()
+        .with_writer(file)
+        .with_trace_config(trace_config(config))
+        .install_simple();
+    Ok(tracer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use actix_web::{test, App};
+    use std::{env, fs};
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn file_exporter_writes_the_request_span_and_its_children() {
+        let path = env::temp_dir().join(format!("spans-{}.txt", Uuid::new_v4()));
+        let config = TracingConfig {
+            exporter: TraceExporter::File,
+            file: Some(path.clone()),
+            ..TracingConfig::default()
+        };
+        let tracer = file_tracer(&config).unwrap();
+        // only for this thread, which the whole test runs on, so other tests stay untraced
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        let pool = test_support::pool().await;
+        let mut app = test::init_service(
+            App::new()
+                .data(pool)
+                .wrap(RequestTracing)
+                .configure(crate::user::init),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/users").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert!(res.status().is_success());
+
+        drop(guard);
+        shutdown();
+        let spans = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        // the stdout exporter writes each span's Debug output on a line of its own
+        let span = |name: &str| {
+            spans
+                .lines()
+                .find(|line| line.contains(&format!("name: \"{}\"", name)))
+                .unwrap_or_else(|| panic!("no {} span in {}", name, spans))
+        };
+        let id = |line: &str, field: &str| line.split(field).nth(1).unwrap()[..16].to_string();
+
+        let request = span("GET /users");
+        assert!(request.contains(r#"Key("route"): String("/users")"#));
+        assert!(request.contains(r#"Key("status"): String("200")"#));
+        let query = span("find_all");
+        assert_eq!(id(query, "parent_span_id: "), id(request, ", span_id: "));
+    }
+}
@@ -0,0 +1,43 @@
+// shared setup for the tests that need the database at DATABASE_URL, which has to be migrated
+use crate::config::PasswordConfig;
+use crate::password::Passwords;
+use crate::user::{User, UserPostRequest};
+use dotenv::dotenv;
+use sqlx::PgPool;
+use std::env;
+use uuid::Uuid;
+
+pub const PASSWORD: &str = "wobbly-lantern-42";
+
+pub async fn pool() -> PgPool {
+    dotenv().ok();
+    let url = env::var("DATABASE_URL").expect("DATABASE_URL must be set to run the tests");
+    PgPool::connect(&url)
+        .await
+        .expect("error connecting to the test database")
+}
+
+// the cheapest settings either hasher takes, the tests aren't about hashing
+pub fn passwords() -> Passwords {
+    let config = PasswordConfig {
+        argon2_memory_kib: 1024,
+        argon2_iterations: 1,
+        bcrypt_cost: 4,
+        ..PasswordConfig::default()
+    };
+    Passwords::from_config(&config).expect("error setting up password hashing")
+}
+
+// a fresh account with PASSWORD, named uniquely so tests don't trip over each other
+pub async fn create_user(pool: &PgPool) -> User {
+    let username = format!("test-{}", Uuid::new_v4().to_simple());
+    let new_user = UserPostRequest {
+        name: "Test User".to_string(),
+        username,
+        password: PASSWORD.to_string(),
+        email: None,
+    };
+    User::create(new_user, &passwords(), pool)
+        .await
+        .expect("error creating test user")
+}
@@ -2,7 +2,9 @@ use actix_web::{Error, HttpRequest, HttpResponse, Responder};
 use anyhow::Result;
 use rand::Rng;
 use serde::Serialize;
-use sqlx::{FromRow, PgPool};
+use sha2::{Digest, Sha256};
+use sqlx::{Done, FromRow, PgPool};
+use tracing::instrument;
 use uuid::Uuid;
 
 #[derive(Serialize, FromRow)]
@@ -27,10 +29,11 @@ impl Token {
         Token {
             id: Uuid::new_v4(),
             value: random,
-            user_id: user_id,
+            user_id,
         }
     }
 
+    #[instrument(skip_all)]
     pub async fn create(user_id: Uuid, pool: &PgPool) -> Result<Token> {
         let token = Token::new(user_id);
 
@@ -66,9 +69,29 @@ impl Token {
             user_id: rec.user_id,
         })
     }
+
+    #[instrument(skip_all)]
+    pub async fn delete_by_user(user_id: Uuid, pool: &PgPool) -> Result<u64> {
+        let mut tx = pool.begin().await?;
+
+        let n_deleted = sqlx::query!(
+            r#"
+            DELETE FROM tokens
+            WHERE user_id = $1
+            "#,
+            user_id,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(n_deleted)
+    }
 }
 
-fn generate_random_u8s(amount: usize) -> Vec<u8> {
+pub fn generate_random_u8s(amount: usize) -> Vec<u8> {
     let mut v = Vec::new();
     for _ in 0..amount {
         let u: u8 = rand::thread_rng().gen();
@@ -76,3 +99,8 @@ fn generate_random_u8s(amount: usize) -> Vec<u8> {
     }
     v
 }
+
+// for secrets that are only ever compared, never read back
+pub fn hash_secret(value: &str) -> String {
+    base64::encode(Sha256::digest(value.as_bytes()))
+}
@@ -0,0 +1,6 @@
+mod model;
+mod routes;
+pub mod totp;
+
+pub use model::*;
+pub use routes::init;
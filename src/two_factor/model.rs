@@ -0,0 +1,309 @@
+use crate::token::{generate_random_u8s, hash_secret};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Done, PgPool};
+use uuid::Uuid;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Serialize, Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LoginChallengeRequest {
+    pub challenge: String,
+    // either a code from the authenticator app or one of the recovery codes
+    pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct TotpSetupResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct LoginChallengeResponse {
+    pub challenge: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub struct TotpCredential {
+    pub secret: String,
+    pub enabled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct LoginChallenge {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl TotpCredential {
+    pub async fn find_by_user(user_id: Uuid, pool: &PgPool) -> Result<Option<TotpCredential>> {
+        let rec = sqlx::query!(
+            r#"
+            SELECT secret, enabled_at
+            FROM totp_credentials
+            WHERE user_id = $1
+            "#,
+            user_id,
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(rec.map(|rec| TotpCredential {
+            secret: rec.secret,
+            enabled_at: rec.enabled_at,
+        }))
+    }
+
+    // stores a secret that still has to be confirmed, replacing an earlier unconfirmed one
+    pub async fn create_pending(
+        user_id: Uuid,
+        secret: &str,
+        pool: &PgPool,
+    ) -> Result<Option<TotpCredential>> {
+        let mut tx = pool.begin().await?;
+
+        let n_upserted = sqlx::query!(
+            r#"
+            INSERT INTO totp_credentials (user_id, secret)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET secret = EXCLUDED.secret, last_used_step = NULL
+            WHERE totp_credentials.enabled_at IS NULL
+            "#,
+            user_id,
+            secret,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        // 2FA is already enabled, the secret in use is left alone
+        if n_upserted == 0 {
+            return Ok(None);
+        }
+
+        tx.commit().await?;
+
+        Ok(Some(TotpCredential {
+            secret: secret.to_string(),
+            enabled_at: None,
+        }))
+    }
+
+    pub async fn enable(user_id: Uuid, step: i64, pool: &PgPool) -> Result<u64> {
+        let mut tx = pool.begin().await?;
+
+        let n_updated = sqlx::query!(
+            r#"
+            UPDATE totp_credentials
+            SET enabled_at = now(), last_used_step = $1
+            WHERE user_id = $2 AND enabled_at IS NULL
+            "#,
+            step,
+            user_id,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(n_updated)
+    }
+
+    // a code is only good once, so the step it matched must be newer than the last one used
+    pub async fn use_step(user_id: Uuid, step: i64, pool: &PgPool) -> Result<bool> {
+        let mut tx = pool.begin().await?;
+
+        let n_updated = sqlx::query!(
+            r#"
+            UPDATE totp_credentials
+            SET last_used_step = $1
+            WHERE user_id = $2 AND enabled_at IS NOT NULL
+            AND (last_used_step IS NULL OR last_used_step < $1)
+            "#,
+            step,
+            user_id,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(n_updated > 0)
+    }
+}
+
+pub struct RecoveryCode;
+
+impl RecoveryCode {
+    // throws away the previous set and returns the new plain codes, only their hashes are stored
+    pub async fn regenerate(user_id: Uuid, pool: &PgPool) -> Result<Vec<String>> {
+        let codes = (0..RECOVERY_CODE_COUNT)
+            .map(|_| generate_recovery_code())
+            .collect::<Vec<String>>();
+
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM recovery_codes
+            WHERE user_id = $1
+            "#,
+            user_id,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        for code in &codes {
+            sqlx::query!(
+                r#"
+                INSERT INTO recovery_codes (id, code_hash, user_id)
+                VALUES ($1, $2, $3)
+                "#,
+                Uuid::new_v4(),
+                hash_secret(&normalize_recovery_code(code)),
+                user_id,
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(codes)
+    }
+
+    pub async fn redeem(user_id: Uuid, code: &str, pool: &PgPool) -> Result<bool> {
+        let mut tx = pool.begin().await?;
+
+        let n_updated = sqlx::query!(
+            r#"
+            UPDATE recovery_codes
+            SET used_at = now()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+            "#,
+            user_id,
+            hash_secret(&normalize_recovery_code(code)),
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(n_updated > 0)
+    }
+}
+
+impl LoginChallenge {
+    // only the hash is stored, so the plain value is handed back to the caller
+    pub async fn create(
+        user_id: Uuid,
+        ttl: Duration,
+        pool: &PgPool,
+    ) -> Result<(LoginChallenge, String)> {
+        let value = base64::encode_config(generate_random_u8s(24), base64::URL_SAFE_NO_PAD);
+        let challenge = LoginChallenge {
+            id: Uuid::new_v4(),
+            user_id,
+            expires_at: Utc::now() + ttl,
+        };
+
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO login_challenges (id, value_hash, user_id, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+            challenge.id,
+            hash_secret(&value),
+            challenge.user_id,
+            challenge.expires_at,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok((challenge, value))
+    }
+
+    // a challenge can only be answered once, right or wrong
+    pub async fn redeem(value: &str, pool: &PgPool) -> Result<Option<Uuid>> {
+        let mut tx = pool.begin().await?;
+
+        let rec = sqlx::query!(
+            r#"
+            DELETE FROM login_challenges
+            WHERE value_hash = $1 AND expires_at > now()
+            RETURNING user_id
+            "#,
+            hash_secret(value),
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(rec.map(|rec| rec.user_id))
+    }
+}
+
+// ten characters from the base32 alphabet, shown as two groups of five
+fn generate_recovery_code() -> String {
+    let encoded = base32::encode(
+        base32::Alphabet::RFC4648 { padding: false },
+        &generate_random_u8s(8),
+    )
+    .to_lowercase();
+    format!("{}-{}", &encoded[..5], &encoded[5..10])
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use crate::two_factor::totp;
+    use crate::user::User;
+
+    #[actix_rt::test]
+    async fn a_step_is_only_accepted_once() {
+        let pool = test_support::pool().await;
+        let user = test_support::create_user(&pool).await;
+        TotpCredential::create_pending(user.id, &totp::generate_secret(), &pool)
+            .await
+            .unwrap();
+        // confirming the setup uses up its step
+        TotpCredential::enable(user.id, 100, &pool).await.unwrap();
+
+        assert!(!TotpCredential::use_step(user.id, 100, &pool).await.unwrap());
+        assert!(!TotpCredential::use_step(user.id, 99, &pool).await.unwrap());
+        assert!(TotpCredential::use_step(user.id, 101, &pool).await.unwrap());
+        assert!(!TotpCredential::use_step(user.id, 101, &pool).await.unwrap());
+
+        User::delete(user.id, &pool).await.unwrap();
+    }
+}
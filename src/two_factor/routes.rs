@@ -0,0 +1,188 @@
+use crate::audit::{self, AuditAction, AuditTarget};
+use crate::auth::{self, Scope};
+use crate::token::Token;
+use crate::two_factor::{
+    totp, LoginChallenge, LoginChallengeRequest, RecoveryCode, RecoveryCodesResponse,
+    TotpCodeRequest, TotpCredential, TotpSetupResponse,
+};
+use crate::user::User;
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use chrono::Utc;
+use log::error;
+use serde_json::json;
+use sqlx::PgPool;
+
+// shown as the account's label in authenticator apps
+const TOTP_ISSUER: &str = "actixweb-sqlx-sample";
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(setup)
+        .service(enable)
+        .service(login_second_step);
+}
+
+#[post("/users/2fa/setup")]
+async fn setup(credentials: BearerAuth, db_pool: web::Data<PgPool>) -> impl Responder {
+    let result = auth::validate_bearer_auth(credentials, Scope::Account, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    let secret = totp::generate_secret();
+    let result = TotpCredential::create_pending(user.id, &secret, db_pool.get_ref()).await;
+    match result {
+        Ok(Some(credential)) => HttpResponse::Ok().json(TotpSetupResponse {
+            otpauth_uri: totp::otpauth_uri(&credential.secret, TOTP_ISSUER, &user.username),
+            secret: credential.secret,
+        }),
+        Ok(None) => HttpResponse::BadRequest().body("Two-factor authentication is already enabled"),
+        Err(err) => {
+            error!("error creating totp credential: {}", err);
+            HttpResponse::InternalServerError()
+                .body("Error trying to set up two-factor authentication")
+        }
+    }
+}
+
+#[post("/users/2fa/enable")]
+async fn enable(
+    credentials: BearerAuth,
+    request: web::Json<TotpCodeRequest>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result = auth::validate_bearer_auth(credentials, Scope::Account, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    let credential = match TotpCredential::find_by_user(user.id, db_pool.get_ref()).await {
+        Ok(Some(credential)) if credential.enabled_at.is_none() => credential,
+        Ok(Some(_)) => {
+            return HttpResponse::BadRequest().body("Two-factor authentication is already enabled")
+        }
+        Ok(None) => {
+            return HttpResponse::BadRequest().body("Two-factor authentication has not been set up")
+        }
+        Err(err) => {
+            error!("error fetching totp credential: {}", err);
+            return HttpResponse::InternalServerError()
+                .body("Error trying to read two-factor settings from database");
+        }
+    };
+
+    let step = match totp::verify(&credential.secret, &request.code, Utc::now().timestamp()) {
+        Some(step) => step,
+        None => return HttpResponse::BadRequest().body("Code is incorrect"),
+    };
+
+    if let Err(err) = TotpCredential::enable(user.id, step, db_pool.get_ref()).await {
+        error!("error enabling totp credential: {}", err);
+        return HttpResponse::InternalServerError()
+            .body("Error trying to enable two-factor authentication");
+    }
+
+    let result = RecoveryCode::regenerate(user.id, db_pool.get_ref()).await;
+    match result {
+        Ok(recovery_codes) => HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes }),
+        Err(err) => {
+            error!("error creating recovery codes: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to create recovery codes")
+        }
+    }
+}
+
+#[post("/users/login/2fa")]
+async fn login_second_step(
+    req: HttpRequest,
+    request: web::Json<LoginChallengeRequest>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let user_id = match LoginChallenge::redeem(&request.challenge, db_pool.get_ref()).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => {
+            return HttpResponse::Unauthorized().body("Login challenge is invalid or expired")
+        }
+        Err(err) => {
+            error!("error redeeming login challenge: {}", err);
+            return HttpResponse::InternalServerError()
+                .body("Error trying to redeem login challenge");
+        }
+    };
+
+    // the account may have been suspended since the password was checked
+    let user = match User::find_by_id(user_id, db_pool.get_ref()).await {
+        Ok(Some(user)) => match auth::ensure_not_suspended(user) {
+            Ok(user) => user,
+            Err(err) => return HttpResponse::from_error(err),
+        },
+        Ok(None) => return HttpResponse::NotFound().body("User not found"),
+        Err(err) => {
+            error!("error fetching user: {}", err);
+            return HttpResponse::InternalServerError()
+                .body("Error trying to read user from database");
+        }
+    };
+
+    let valid = match check_code(&user, &request.code, db_pool.get_ref()).await {
+        Ok(valid) => valid,
+        Err(err) => {
+            error!("error checking two-factor code: {}", err);
+            return HttpResponse::InternalServerError().body("Error trying to check code");
+        }
+    };
+    if !valid {
+        audit::record(
+            &req,
+            AuditAction::LoginFailed,
+            None,
+            Some(AuditTarget::User(user.id)),
+            json!({ "method": "totp", "username": user.username }),
+            db_pool.get_ref(),
+        )
+        .await;
+        return HttpResponse::Unauthorized().body("Code is incorrect");
+    }
+
+    let token = Token::create(user.id, db_pool.get_ref()).await;
+    match token {
+        Ok(token) => {
+            audit::record(
+                &req,
+                AuditAction::Login,
+                Some(user.id),
+                None,
+                json!({ "method": "totp", "token_id": token.id }),
+                db_pool.get_ref(),
+            )
+            .await;
+            HttpResponse::Ok().json(token)
+        }
+        Err(err) => {
+            error!("error creating token: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to create new token")
+        }
+    }
+}
+
+// six digits are treated as a TOTP code, anything else as a recovery code
+async fn check_code(user: &User, code: &str, pool: &PgPool) -> anyhow::Result<bool> {
+    if code.len() != 6 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return RecoveryCode::redeem(user.id, code, pool).await;
+    }
+
+    let credential = match TotpCredential::find_by_user(user.id, pool).await? {
+        Some(credential) => credential,
+        None => return Ok(false),
+    };
+    match totp::verify(&credential.secret, code, Utc::now().timestamp()) {
+        Some(step) => TotpCredential::use_step(user.id, step, pool).await,
+        None => Ok(false),
+    }
+}
@@ -1,8 +1,11 @@
+use crate::password::Passwords;
 use actix_web::{Error, HttpRequest, HttpResponse, Responder};
 use anyhow::Result;
-use bcrypt::{hash, DEFAULT_COST};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgDatabaseError;
 use sqlx::{Done, FromRow, PgPool};
+use tracing::{instrument, warn};
 use uuid::Uuid;
 
 // this struct will use to receive user input
@@ -11,12 +14,46 @@ pub struct UserPostRequest {
     pub name: String,
     pub username: String,
     pub password: String,
+    pub email: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct UserPutRequest {
     pub name: String,
     pub username: String,
+    // left out keeps the current address, null clears it
+    #[serde(default, deserialize_with = "present")]
+    pub email: Option<Option<String>>,
+}
+
+// serde reads both a missing field and null as None, this keeps null apart as Some(None)
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Deserialize::deserialize(deserializer).map(Some)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub code: String,
+    pub new: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DisableRequest {
+    pub reason: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -32,6 +69,42 @@ pub struct User {
     pub name: String,
     pub username: String,
     pub password: String,
+    pub role: Role,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub disabled_reason: Option<String>,
+    pub email: Option<String>,
+    pub email_verified_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    // the column has a CHECK, anything unexpected falls back to the least privileged role
+    pub fn from_db(value: &str) -> Role {
+        match value {
+            "user" => Role::User,
+            "admin" => Role::Admin,
+            "moderator" => Role::Moderator,
+            _ => {
+                warn!("unknown role {:?}, treating it as user", value);
+                Role::User
+            }
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
 }
 
 // hide password
@@ -52,6 +125,34 @@ impl Responder for UserPublic {
     }
 }
 
+// everything but the password, only served to admins
+#[derive(Serialize)]
+pub struct UserAdmin {
+    pub id: Uuid,
+    pub name: String,
+    pub username: String,
+    pub role: Role,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub disabled_reason: Option<String>,
+    pub email: Option<String>,
+    pub email_verified_at: Option<DateTime<Utc>>,
+}
+
+impl From<User> for UserAdmin {
+    fn from(user: User) -> Self {
+        UserAdmin {
+            id: user.id,
+            name: user.name,
+            username: user.username,
+            role: user.role,
+            disabled_at: user.disabled_at,
+            disabled_reason: user.disabled_reason,
+            email: user.email,
+            email_verified_at: user.email_verified_at,
+        }
+    }
+}
+
 impl From<User> for UserPublic {
     fn from(user: User) -> Self {
         UserPublic {
@@ -63,10 +164,12 @@ impl From<User> for UserPublic {
 }
 
 impl User {
+    #[instrument(skip_all)]
     pub async fn find_all(pool: &PgPool) -> Result<Vec<User>> {
         let users = sqlx::query!(
             r#"
-            SELECT id, name, username, password
+            SELECT id, name, username, password, role, disabled_at, disabled_reason,
+                email, email_verified_at
             FROM users
             "#
         )
@@ -78,16 +181,23 @@ impl User {
             name: rec.name,
             username: rec.username,
             password: rec.password,
+            role: Role::from_db(&rec.role),
+            disabled_at: rec.disabled_at,
+            disabled_reason: rec.disabled_reason,
+            email: rec.email,
+            email_verified_at: rec.email_verified_at,
         })
         .collect();
 
         Ok(users)
     }
 
+    #[instrument(skip_all)]
     pub async fn find_by_id(id: Uuid, pool: &PgPool) -> Result<Option<User>> {
         let rec = sqlx::query!(
             r#"
-            SELECT id, name, username, password
+            SELECT id, name, username, password, role, disabled_at, disabled_reason,
+                email, email_verified_at
             FROM users
             WHERE id = $1
             "#,
@@ -101,13 +211,20 @@ impl User {
             name: rec.name,
             username: rec.username,
             password: rec.password,
+            role: Role::from_db(&rec.role),
+            disabled_at: rec.disabled_at,
+            disabled_reason: rec.disabled_reason,
+            email: rec.email,
+            email_verified_at: rec.email_verified_at,
         }))
     }
 
+    #[instrument(skip_all)]
     pub async fn find_by_username(username: &str, pool: &PgPool) -> Result<Option<User>> {
         let rec = sqlx::query!(
             r#"
-            SELECT id, name, username, password
+            SELECT id, name, username, password, role, disabled_at, disabled_reason,
+                email, email_verified_at
             FROM users
             WHERE username = $1
             "#,
@@ -121,31 +238,72 @@ impl User {
             name: rec.name,
             username: rec.username,
             password: rec.password,
+            role: Role::from_db(&rec.role),
+            disabled_at: rec.disabled_at,
+            disabled_reason: rec.disabled_reason,
+            email: rec.email,
+            email_verified_at: rec.email_verified_at,
         }))
     }
 
-    pub async fn create(user: UserPostRequest, pool: &PgPool) -> Result<User> {
+    #[instrument(skip_all)]
+    pub async fn find_by_email(email: &str, pool: &PgPool) -> Result<Option<User>> {
+        let rec = sqlx::query!(
+            r#"
+            SELECT id, name, username, password, role, disabled_at, disabled_reason,
+                email, email_verified_at
+            FROM users
+            WHERE email = $1
+            "#,
+            email,
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(rec.map(|rec| User {
+            id: rec.id,
+            name: rec.name,
+            username: rec.username,
+            password: rec.password,
+            role: Role::from_db(&rec.role),
+            disabled_at: rec.disabled_at,
+            disabled_reason: rec.disabled_reason,
+            email: rec.email,
+            email_verified_at: rec.email_verified_at,
+        }))
+    }
+
+    #[instrument(skip_all)]
+    pub async fn create(
+        user: UserPostRequest,
+        passwords: &Passwords,
+        pool: &PgPool,
+    ) -> Result<User> {
+        passwords.check_policy(&user.password, &user.username, &user.name)?;
+
         let user_id = Uuid::new_v4();
-        let hashed_password = hash(user.password, DEFAULT_COST)?;
+        let hashed_password = passwords.hash(&user.password).await?;
 
         let mut tx = pool.begin().await?;
 
         sqlx::query!(
             r#"
-            INSERT INTO users (id, name, username, password)
-            VALUES ($1, $2, $3, $4)
+            INSERT INTO users (id, name, username, password, email)
+            VALUES ($1, $2, $3, $4, $5)
             "#,
             user_id,
             user.name,
             user.username,
             hashed_password,
+            user.email,
         )
         .execute(&mut tx)
         .await?;
 
         let rec = sqlx::query!(
             r#"
-            SELECT id, name, username, password
+            SELECT id, name, username, password, role, disabled_at, disabled_reason,
+                email, email_verified_at
             FROM users
             WHERE id = $1
             "#,
@@ -161,20 +319,32 @@ impl User {
             name: rec.name,
             username: rec.username,
             password: rec.password,
+            role: Role::from_db(&rec.role),
+            disabled_at: rec.disabled_at,
+            disabled_reason: rec.disabled_reason,
+            email: rec.email,
+            email_verified_at: rec.email_verified_at,
         })
     }
 
+    #[instrument(skip_all)]
     pub async fn update(id: Uuid, user: UserPutRequest, pool: &PgPool) -> Result<Option<User>> {
         let mut tx = pool.begin().await?;
 
         let n_updated = sqlx::query!(
             r#"
             UPDATE users 
-            SET name = $1, username = $2
-            WHERE id = $3
+            SET name = $1, username = $2,
+                email = CASE WHEN $3 THEN $4 ELSE email END,
+                email_verified_at = CASE
+                    WHEN NOT $3 OR email IS NOT DISTINCT FROM $4 THEN email_verified_at
+                END
+            WHERE id = $5
             "#,
             user.name,
             user.username,
+            user.email.is_some(),
+            user.email.flatten(),
             id,
         )
         .execute(&mut tx)
@@ -187,7 +357,8 @@ impl User {
 
         let user = sqlx::query!(
             r#"
-            SELECT id, name, username, password
+            SELECT id, name, username, password, role, disabled_at, disabled_reason,
+                email, email_verified_at
             FROM users
             WHERE id = $1
             "#,
@@ -200,6 +371,11 @@ impl User {
             name: rec.name,
             username: rec.username,
             password: rec.password,
+            role: Role::from_db(&rec.role),
+            disabled_at: rec.disabled_at,
+            disabled_reason: rec.disabled_reason,
+            email: rec.email,
+            email_verified_at: rec.email_verified_at,
         })?;
 
         tx.commit().await?;
@@ -207,8 +383,41 @@ impl User {
         Ok(Some(user))
     }
 
-    pub async fn update_password(id: Uuid, password: &str, pool: &PgPool) -> Result<Option<User>> {
-        let hashed_password = hash(password, DEFAULT_COST)?;
+    #[instrument(skip_all)]
+    pub async fn update_password(
+        id: Uuid,
+        password: &str,
+        passwords: &Passwords,
+        pool: &PgPool,
+    ) -> Result<Option<User>> {
+        let user = match User::find_by_id(id, pool).await? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        passwords.check_policy(password, &user.username, &user.name)?;
+
+        let hashed_password = passwords.hash(password).await?;
+        User::store_password_hash(id, &hashed_password, pool).await
+    }
+
+    // for moving a password that was already accepted to the current hasher, so no policy check
+    #[instrument(skip_all)]
+    pub async fn rehash_password(
+        id: Uuid,
+        password: &str,
+        passwords: &Passwords,
+        pool: &PgPool,
+    ) -> Result<Option<User>> {
+        let hashed_password = passwords.hash(password).await?;
+        User::store_password_hash(id, &hashed_password, pool).await
+    }
+
+    #[instrument(skip_all)]
+    async fn store_password_hash(
+        id: Uuid,
+        hashed_password: &str,
+        pool: &PgPool,
+    ) -> Result<Option<User>> {
         let mut tx = pool.begin().await?;
 
         let n_updated = sqlx::query!(
@@ -230,7 +439,58 @@ impl User {
 
         let user = sqlx::query!(
             r#"
-            SELECT id, name, username, password
+            SELECT id, name, username, password, role, disabled_at, disabled_reason,
+                email, email_verified_at
+            FROM users
+            WHERE id = $1
+            "#,
+            id,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .map(|rec| User {
+            id: rec.id,
+            name: rec.name,
+            username: rec.username,
+            password: rec.password,
+            role: Role::from_db(&rec.role),
+            disabled_at: rec.disabled_at,
+            disabled_reason: rec.disabled_reason,
+            email: rec.email,
+            email_verified_at: rec.email_verified_at,
+        })?;
+
+        tx.commit().await?;
+
+        Ok(Some(user))
+    }
+
+    // only succeeds while the address on the account is still the one the code was sent to
+    #[instrument(skip_all)]
+    pub async fn verify_email(id: Uuid, email: &str, pool: &PgPool) -> Result<Option<User>> {
+        let mut tx = pool.begin().await?;
+
+        let n_updated = sqlx::query!(
+            r#"
+            UPDATE users
+            SET email_verified_at = now()
+            WHERE id = $1 AND email = $2
+            "#,
+            id,
+            email,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        if n_updated == 0 {
+            return Ok(None);
+        }
+
+        let user = sqlx::query!(
+            r#"
+            SELECT id, name, username, password, role, disabled_at, disabled_reason,
+                email, email_verified_at
             FROM users
             WHERE id = $1
             "#,
@@ -243,6 +503,11 @@ impl User {
             name: rec.name,
             username: rec.username,
             password: rec.password,
+            role: Role::from_db(&rec.role),
+            disabled_at: rec.disabled_at,
+            disabled_reason: rec.disabled_reason,
+            email: rec.email,
+            email_verified_at: rec.email_verified_at,
         })?;
 
         tx.commit().await?;
@@ -250,6 +515,154 @@ impl User {
         Ok(Some(user))
     }
 
+    // tokens are kept, auth::ensure_not_suspended rejects them until the account is reinstated
+    #[instrument(skip_all)]
+    pub async fn disable(id: Uuid, reason: &str, pool: &PgPool) -> Result<Option<User>> {
+        let mut tx = pool.begin().await?;
+
+        let n_updated = sqlx::query!(
+            r#"
+            UPDATE users
+            SET disabled_at = now(), disabled_reason = $1
+            WHERE id = $2 AND disabled_at IS NULL
+            "#,
+            reason,
+            id,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        if n_updated == 0 {
+            return Ok(None);
+        }
+
+        let user = sqlx::query!(
+            r#"
+            SELECT id, name, username, password, role, disabled_at, disabled_reason,
+                email, email_verified_at
+            FROM users
+            WHERE id = $1
+            "#,
+            id,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .map(|rec| User {
+            id: rec.id,
+            name: rec.name,
+            username: rec.username,
+            password: rec.password,
+            role: Role::from_db(&rec.role),
+            disabled_at: rec.disabled_at,
+            disabled_reason: rec.disabled_reason,
+            email: rec.email,
+            email_verified_at: rec.email_verified_at,
+        })?;
+
+        tx.commit().await?;
+
+        Ok(Some(user))
+    }
+
+    #[instrument(skip_all)]
+    pub async fn reinstate(id: Uuid, pool: &PgPool) -> Result<Option<User>> {
+        let mut tx = pool.begin().await?;
+
+        let n_updated = sqlx::query!(
+            r#"
+            UPDATE users
+            SET disabled_at = NULL, disabled_reason = NULL
+            WHERE id = $1 AND disabled_at IS NOT NULL
+            "#,
+            id,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        if n_updated == 0 {
+            return Ok(None);
+        }
+
+        let user = sqlx::query!(
+            r#"
+            SELECT id, name, username, password, role, disabled_at, disabled_reason,
+                email, email_verified_at
+            FROM users
+            WHERE id = $1
+            "#,
+            id,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .map(|rec| User {
+            id: rec.id,
+            name: rec.name,
+            username: rec.username,
+            password: rec.password,
+            role: Role::from_db(&rec.role),
+            disabled_at: rec.disabled_at,
+            disabled_reason: rec.disabled_reason,
+            email: rec.email,
+            email_verified_at: rec.email_verified_at,
+        })?;
+
+        tx.commit().await?;
+
+        Ok(Some(user))
+    }
+
+    #[instrument(skip_all)]
+    pub async fn set_role(id: Uuid, role: Role, pool: &PgPool) -> Result<Option<User>> {
+        let mut tx = pool.begin().await?;
+
+        let n_updated = sqlx::query!(
+            r#"
+            UPDATE users
+            SET role = $1
+            WHERE id = $2
+            "#,
+            role.as_str(),
+            id,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        if n_updated == 0 {
+            return Ok(None);
+        }
+
+        let user = sqlx::query!(
+            r#"
+            SELECT id, name, username, password, role, disabled_at, disabled_reason,
+                email, email_verified_at
+            FROM users
+            WHERE id = $1
+            "#,
+            id,
+        )
+        .fetch_one(&mut tx)
+        .await
+        .map(|rec| User {
+            id: rec.id,
+            name: rec.name,
+            username: rec.username,
+            password: rec.password,
+            role: Role::from_db(&rec.role),
+            disabled_at: rec.disabled_at,
+            disabled_reason: rec.disabled_reason,
+            email: rec.email,
+            email_verified_at: rec.email_verified_at,
+        })?;
+
+        tx.commit().await?;
+
+        Ok(Some(user))
+    }
+
+    #[instrument(skip_all)]
     pub async fn delete(id: Uuid, pool: &PgPool) -> Result<u64> {
         let mut tx = pool.begin().await?;
 
@@ -269,10 +682,13 @@ impl User {
         Ok(n_deleted)
     }
 
+    #[instrument(skip_all)]
     pub async fn find_by_post(post_id: Uuid, pool: &PgPool) -> Result<Option<User>> {
         let rec = sqlx::query!(
             r#"
-            SELECT users.id, users.name, users.username, users.password
+            SELECT users.id, users.name, users.username, users.password,
+                users.role, users.disabled_at, users.disabled_reason,
+                users.email, users.email_verified_at
             FROM posts inner join users
             ON posts.user_id = users.id
             WHERE posts.id = $1
@@ -287,13 +703,21 @@ impl User {
             name: rec.name,
             username: rec.username,
             password: rec.password,
+            role: Role::from_db(&rec.role),
+            disabled_at: rec.disabled_at,
+            disabled_reason: rec.disabled_reason,
+            email: rec.email,
+            email_verified_at: rec.email_verified_at,
         }))
     }
 
+    #[instrument(skip_all)]
     pub async fn find_by_token(token_value: &str, pool: &PgPool) -> Result<Option<User>> {
         let rec = sqlx::query!(
             r#"
-            SELECT users.id, users.name, users.username, users.password
+            SELECT users.id, users.name, users.username, users.password,
+                users.role, users.disabled_at, users.disabled_reason,
+                users.email, users.email_verified_at
             FROM tokens inner join users
             ON tokens.user_id = users.id
             WHERE tokens.value = $1
@@ -308,6 +732,22 @@ impl User {
             name: rec.name,
             username: rec.username,
             password: rec.password,
+            role: Role::from_db(&rec.role),
+            disabled_at: rec.disabled_at,
+            disabled_reason: rec.disabled_reason,
+            email: rec.email,
+            email_verified_at: rec.email_verified_at,
         }))
     }
 }
+
+// the UNIQUE on users.email, hit when a create or update asks for an address someone else has
+pub fn is_email_taken(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(err)) => {
+            let err = err.downcast_ref::<PgDatabaseError>();
+            err.code() == "23505" && err.constraint() == Some("users_email_key")
+        }
+        _ => false,
+    }
+}
@@ -1,13 +1,25 @@
-use crate::auth;
+use crate::audit::{self, AuditAction, AuditTarget};
+use crate::auth::{self, LoginThrottle, Scope};
+use crate::config::TokenConfig;
+use crate::email_verification::{EmailSettings, EmailVerification};
+use crate::mail::{Mailer, Message};
+use crate::password::{Passwords, PolicyError};
 use crate::post::Post;
+use crate::reset_code::ResetCode;
 use crate::token::Token;
-use crate::user::{PasswordRequest, User, UserPostRequest, UserPublic, UserPutRequest};
-use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use crate::two_factor::{LoginChallenge, LoginChallengeResponse, TotpCredential};
+use crate::user::{
+    is_email_taken, ForgotPasswordRequest, PasswordRequest, ResetPasswordRequest, User,
+    UserPostRequest, UserPublic, UserPutRequest, VerifyEmailRequest,
+};
+use actix_web::{delete, get, post, put, rt, web, HttpRequest, HttpResponse, Responder};
 use actix_web_httpauth::extractors::basic::BasicAuth;
 use actix_web_httpauth::extractors::bearer::BearerAuth;
-use bcrypt::verify;
+use chrono::Duration;
 use log::error;
+use serde_json::json;
 use sqlx::PgPool;
+use std::sync::Arc;
 use uuid::Uuid;
 
 pub fn init(cfg: &mut web::ServiceConfig) {
@@ -16,6 +28,10 @@ pub fn init(cfg: &mut web::ServiceConfig) {
         .service(create)
         .service(update)
         .service(update_password)
+        .service(forgot_password)
+        .service(reset_password)
+        .service(verify_email)
+        .service(resend_verification)
         .service(delete)
         .service(find_posts)
         .service(login);
@@ -28,7 +44,7 @@ async fn find_all(db_pool: web::Data<PgPool>) -> impl Responder {
         Ok(users) => {
             let users = users
                 .into_iter()
-                .map(|user| UserPublic::from(user))
+                .map(UserPublic::from)
                 .collect::<Vec<UserPublic>>();
             HttpResponse::Ok().json(users)
         }
@@ -53,11 +69,35 @@ async fn find(id: web::Path<Uuid>, db_pool: web::Data<PgPool>) -> impl Responder
 }
 
 #[post("/users")]
-async fn create(user: web::Json<UserPostRequest>, db_pool: web::Data<PgPool>) -> impl Responder {
-    let result = User::create(user.into_inner(), db_pool.get_ref()).await;
+async fn create(
+    user: web::Json<UserPostRequest>,
+    passwords: web::Data<Passwords>,
+    db_pool: web::Data<PgPool>,
+    mailer: web::Data<Arc<dyn Mailer>>,
+    email_settings: web::Data<EmailSettings>,
+) -> impl Responder {
+    if !user.email.as_deref().is_none_or(is_valid_email) {
+        return HttpResponse::BadRequest().body("Email address is invalid");
+    }
+
+    let result = User::create(user.into_inner(), &passwords, db_pool.get_ref()).await;
     match result {
-        Ok(user) => HttpResponse::Ok().json(UserPublic::from(user)),
+        Ok(user) => {
+            // the account is usable either way, a lost mail can be resent via /users/email/resend
+            let mailer = mailer.get_ref().as_ref();
+            let sent = send_verification(&user, db_pool.get_ref(), mailer, &email_settings).await;
+            if let Err(err) = sent {
+                error!("error sending email verification: {}", err);
+            }
+            HttpResponse::Ok().json(UserPublic::from(user))
+        }
         Err(err) => {
+            if let Some(violations) = err.downcast_ref::<PolicyError>() {
+                return HttpResponse::UnprocessableEntity().json(violations);
+            }
+            if is_email_taken(&err) {
+                return HttpResponse::Conflict().body("Email address is already in use");
+            }
             error!("error creating user: {}", err);
             HttpResponse::InternalServerError().body("Error trying to create new user")
         }
@@ -69,19 +109,60 @@ async fn update(
     credentials: BearerAuth,
     new_user: web::Json<UserPutRequest>,
     db_pool: web::Data<PgPool>,
+    mailer: web::Data<Arc<dyn Mailer>>,
+    email_settings: web::Data<EmailSettings>,
 ) -> impl Responder {
-    let user = match auth::validate_bearer_auth(credentials, db_pool.get_ref()).await {
-        Ok(user) => user,
+    let result = auth::authenticate_bearer(credentials, db_pool.get_ref()).await;
+    let grant = match result {
+        Ok(grant) => grant,
         Err(err) => {
             return HttpResponse::from_error(err);
         }
     };
+    if let Err(err) = grant.require(Scope::UsersWrite) {
+        return HttpResponse::from_error(err);
+    }
+    let user = &grant.user;
+
+    if !new_user
+        .email
+        .as_ref()
+        .is_none_or(|email| email.as_deref().is_none_or(is_valid_email))
+    {
+        return HttpResponse::BadRequest().body("Email address is invalid");
+    }
+
+    // reset codes go to the verified email address, or the username without one, so changing
+    // whichever of the two they go to is as good as changing the password and takes the same scope
+    let email_changed = new_user
+        .email
+        .as_ref()
+        .is_some_and(|email| *email != user.email);
+    let username_changed = user.username != new_user.username;
+    if email_changed || (username_changed && user.email_verified_at.is_none()) {
+        if let Err(err) = grant.require(Scope::Account) {
+            return HttpResponse::from_error(err);
+        }
+    }
 
     let result = User::update(user.id, new_user.into_inner(), db_pool.get_ref()).await;
     match result {
-        Ok(Some(user)) => HttpResponse::Ok().json(UserPublic::from(user)),
+        Ok(Some(user)) => {
+            if email_changed {
+                let mailer = mailer.get_ref().as_ref();
+                let sent =
+                    send_verification(&user, db_pool.get_ref(), mailer, &email_settings).await;
+                if let Err(err) = sent {
+                    error!("error sending email verification: {}", err);
+                }
+            }
+            HttpResponse::Ok().json(UserPublic::from(user))
+        }
         Ok(None) => HttpResponse::NotFound().body("User not found"),
         Err(err) => {
+            if is_email_taken(&err) {
+                return HttpResponse::Conflict().body("Email address is already in use");
+            }
             error!("error updating user: {}", err);
             HttpResponse::InternalServerError().body("Error trying to update user")
         }
@@ -90,36 +171,264 @@ async fn update(
 
 #[put("/users/password")]
 async fn update_password(
+    req: HttpRequest,
     credentials: BearerAuth,
     password: web::Json<PasswordRequest>,
+    passwords: web::Data<Passwords>,
     db_pool: web::Data<PgPool>,
 ) -> impl Responder {
-    let user = match auth::validate_bearer_auth(credentials, db_pool.get_ref()).await {
+    let result = auth::validate_bearer_auth(credentials, Scope::Account, db_pool.get_ref()).await;
+    let user = match result {
         Ok(user) => user,
         Err(err) => {
             return HttpResponse::from_error(err);
         }
     };
 
-    let valid = verify(&password.current.as_bytes(), &user.password).unwrap_or(false);
-    if !valid {
+    let verification = match passwords.verify(&password.current, &user.password).await {
+        Ok(verification) => verification,
+        Err(err) => {
+            error!("error verifying password: {}", err);
+            return HttpResponse::InternalServerError().body("Error trying to verify password");
+        }
+    };
+    if !verification.valid {
         return HttpResponse::BadRequest().body("Current password is incorrect");
     }
 
-    let result = User::update_password(user.id, &password.new, db_pool.get_ref()).await;
+    let result = User::update_password(user.id, &password.new, &passwords, db_pool.get_ref()).await;
     match result {
-        Ok(Some(user)) => HttpResponse::Ok().json(UserPublic::from(user)),
+        Ok(Some(user)) => {
+            audit::record(
+                &req,
+                AuditAction::PasswordChanged,
+                Some(user.id),
+                None,
+                json!({}),
+                db_pool.get_ref(),
+            )
+            .await;
+            HttpResponse::Ok().json(UserPublic::from(user))
+        }
         Ok(None) => HttpResponse::NotFound().body("User not found"),
         Err(err) => {
+            if let Some(violations) = err.downcast_ref::<PolicyError>() {
+                return HttpResponse::UnprocessableEntity().json(violations);
+            }
             error!("error updating user: {}", err);
             HttpResponse::InternalServerError().body("Error trying to update user")
         }
     }
 }
 
+#[post("/users/password/forgot")]
+async fn forgot_password(
+    req: HttpRequest,
+    request: web::Json<ForgotPasswordRequest>,
+    db_pool: web::Data<PgPool>,
+    mailer: web::Data<Arc<dyn Mailer>>,
+    tokens: web::Data<TokenConfig>,
+    throttle: web::Data<LoginThrottle>,
+) -> impl Responder {
+    // every request mails somebody, so it counts against the client address like a failed login
+    if let Err(err) = auth::throttle_address(&req, &throttle, db_pool.get_ref()).await {
+        return HttpResponse::from_error(err);
+    }
+
+    // the lookup and the mail happen after the response, so neither its content nor its timing
+    // tells whether the account exists
+    let username = request.into_inner().username;
+    let pool = db_pool.get_ref().clone();
+    let mailer = mailer.get_ref().clone();
+    let ttl = tokens.reset_code_ttl();
+    rt::spawn(async move {
+        if let Err(err) = send_reset_code(&username, ttl, &pool, mailer.as_ref()).await {
+            error!("error sending reset code: {}", err);
+        }
+    });
+
+    HttpResponse::Ok().body("If the account exists, a reset code has been sent")
+}
+
+async fn send_reset_code(
+    username: &str,
+    ttl: Duration,
+    pool: &PgPool,
+    mailer: &dyn Mailer,
+) -> anyhow::Result<()> {
+    let user = match User::find_by_username(username, pool).await? {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+    let (reset_code, code) = ResetCode::create(user.id, ttl, pool).await?;
+
+    // only a verified address is trusted with the code, otherwise it goes to the username
+    let to = match (user.email, user.email_verified_at) {
+        (Some(email), Some(_)) => email,
+        _ => user.username,
+    };
+    let message = Message {
+        to,
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Use this code to reset your password: {}\nIt expires at {}.",
+            code, reset_code.expires_at
+        ),
+    };
+    mailer.send(&message)
+}
+
+#[post("/users/password/reset")]
+async fn reset_password(
+    req: HttpRequest,
+    request: web::Json<ResetPasswordRequest>,
+    passwords: web::Data<Passwords>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let user_id = match ResetCode::find_user_id(&request.code, db_pool.get_ref()).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => return HttpResponse::BadRequest().body("Reset code is invalid or expired"),
+        Err(err) => {
+            error!("error fetching reset code: {}", err);
+            return HttpResponse::InternalServerError().body("Error trying to read reset code");
+        }
+    };
+
+    let user = match User::find_by_id(user_id, db_pool.get_ref()).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return HttpResponse::NotFound().body("User not found"),
+        Err(err) => {
+            error!("error fetching user: {}", err);
+            return HttpResponse::InternalServerError()
+                .body("Error trying to read user from database");
+        }
+    };
+
+    // checked before the code is used up, so a rejected password doesn't cost the user their code
+    if let Err(violations) = passwords.check_policy(&request.new, &user.username, &user.name) {
+        return HttpResponse::UnprocessableEntity().json(violations);
+    }
+
+    match ResetCode::redeem(&request.code, db_pool.get_ref()).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::BadRequest().body("Reset code is invalid or expired"),
+        Err(err) => {
+            error!("error redeeming reset code: {}", err);
+            return HttpResponse::InternalServerError().body("Error trying to redeem reset code");
+        }
+    }
+
+    let result = User::update_password(user.id, &request.new, &passwords, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(Some(user)) => user,
+        Ok(None) => return HttpResponse::NotFound().body("User not found"),
+        Err(err) => {
+            if let Some(violations) = err.downcast_ref::<PolicyError>() {
+                return HttpResponse::UnprocessableEntity().json(violations);
+            }
+            error!("error updating user: {}", err);
+            return HttpResponse::InternalServerError().body("Error trying to update user");
+        }
+    };
+
+    // whoever knew the old password shouldn't stay signed in
+    if let Err(err) = Token::delete_by_user(user.id, db_pool.get_ref()).await {
+        error!("error deleting tokens: {}", err);
+        return HttpResponse::InternalServerError().body("Error trying to revoke tokens");
+    }
+
+    // nobody is signed in while resetting, the code stands in for the user
+    let target = Some(AuditTarget::User(user.id));
+    audit::record(
+        &req,
+        AuditAction::PasswordReset,
+        None,
+        target,
+        json!({}),
+        db_pool.get_ref(),
+    )
+    .await;
+
+    HttpResponse::Ok().json(UserPublic::from(user))
+}
+
+#[post("/users/email/verify")]
+async fn verify_email(
+    request: web::Json<VerifyEmailRequest>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let (user_id, email) = match EmailVerification::redeem(&request.code, db_pool.get_ref()).await {
+        Ok(Some(redeemed)) => redeemed,
+        Ok(None) => {
+            return HttpResponse::BadRequest().body("Verification code is invalid or expired")
+        }
+        Err(err) => {
+            error!("error redeeming email verification: {}", err);
+            return HttpResponse::InternalServerError()
+                .body("Error trying to redeem email verification");
+        }
+    };
+
+    let result = User::verify_email(user_id, &email, db_pool.get_ref()).await;
+    match result {
+        Ok(Some(user)) => HttpResponse::Ok().json(UserPublic::from(user)),
+        // the address was changed after the code had been sent
+        Ok(None) => HttpResponse::BadRequest().body("Verification code is invalid or expired"),
+        Err(err) => {
+            error!("error verifying email: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to verify email")
+        }
+    }
+}
+
+#[post("/users/email/resend")]
+async fn resend_verification(
+    credentials: BearerAuth,
+    db_pool: web::Data<PgPool>,
+    mailer: web::Data<Arc<dyn Mailer>>,
+    email_settings: web::Data<EmailSettings>,
+) -> impl Responder {
+    let result =
+        auth::validate_bearer_auth(credentials, Scope::UsersWrite, db_pool.get_ref()).await;
+    let user = match result {
+        Ok(user) => user,
+        Err(err) => {
+            return HttpResponse::from_error(err);
+        }
+    };
+
+    if user.email.is_none() {
+        return HttpResponse::BadRequest().body("Account has no email address");
+    }
+    if user.email_verified_at.is_some() {
+        return HttpResponse::BadRequest().body("Email address is already verified");
+    }
+
+    let result = send_verification(
+        &user,
+        db_pool.get_ref(),
+        mailer.get_ref().as_ref(),
+        &email_settings,
+    )
+    .await;
+    match result {
+        Ok(()) => HttpResponse::Ok().body("Verification email has been sent"),
+        Err(err) => {
+            error!("error sending email verification: {}", err);
+            HttpResponse::InternalServerError().body("Error trying to send email verification")
+        }
+    }
+}
+
 #[delete("/users")]
-async fn delete(credentials: BearerAuth, db_pool: web::Data<PgPool>) -> impl Responder {
-    let user = match auth::validate_bearer_auth(credentials, db_pool.get_ref()).await {
+async fn delete(
+    req: HttpRequest,
+    credentials: BearerAuth,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let result =
+        auth::validate_bearer_auth(credentials, Scope::UsersWrite, db_pool.get_ref()).await;
+    let user = match result {
         Ok(user) => user,
         Err(err) => {
             return HttpResponse::from_error(err);
@@ -130,6 +439,17 @@ async fn delete(credentials: BearerAuth, db_pool: web::Data<PgPool>) -> impl Res
     match result {
         Ok(rows_deleted) => {
             if rows_deleted > 0 {
+                let details = json!({ "username": user.username });
+                let target = Some(AuditTarget::User(user.id));
+                audit::record(
+                    &req,
+                    AuditAction::UserDeleted,
+                    Some(user.id),
+                    target,
+                    details,
+                    db_pool.get_ref(),
+                )
+                .await;
                 let msg = format!("Successfully deleted {} record(s)", rows_deleted);
                 HttpResponse::Ok().body(msg)
             } else {
@@ -157,20 +477,116 @@ async fn find_posts(id: web::Path<Uuid>, db_pool: web::Data<PgPool>) -> impl Res
 }
 
 #[post("/users/login")]
-async fn login(credentials: BasicAuth, db_pool: web::Data<PgPool>) -> impl Responder {
-    let user = match auth::validate_basic_auth(credentials, db_pool.get_ref()).await {
+async fn login(
+    req: HttpRequest,
+    credentials: BasicAuth,
+    throttle: web::Data<LoginThrottle>,
+    passwords: web::Data<Passwords>,
+    tokens: web::Data<TokenConfig>,
+    db_pool: web::Data<PgPool>,
+) -> impl Responder {
+    let username = credentials.user_id().to_string();
+    let result =
+        auth::validate_basic_auth(credentials, &req, &throttle, &passwords, db_pool.get_ref())
+            .await;
+    let user = match result {
         Ok(user) => user,
         Err(err) => {
+            // pinned on the account it was aimed at, so its owner sees it in their own log
+            let target = match User::find_by_username(&username, db_pool.get_ref()).await {
+                Ok(Some(user)) => Some(AuditTarget::User(user.id)),
+                _ => None,
+            };
+            let status = err.as_response_error().status_code().as_u16();
+            let details = json!({ "method": "password", "username": username, "status": status });
+            audit::record(
+                &req,
+                AuditAction::LoginFailed,
+                None,
+                target,
+                details,
+                db_pool.get_ref(),
+            )
+            .await;
             return HttpResponse::from_error(err);
         }
     };
 
+    // with 2FA enabled the password only earns a challenge, answered at /users/login/2fa
+    match TotpCredential::find_by_user(user.id, db_pool.get_ref()).await {
+        Ok(Some(credential)) if credential.enabled_at.is_some() => {
+            let ttl = tokens.login_challenge_ttl();
+            let result = LoginChallenge::create(user.id, ttl, db_pool.get_ref()).await;
+            return match result {
+                Ok((challenge, value)) => HttpResponse::Accepted().json(LoginChallengeResponse {
+                    challenge: value,
+                    expires_at: challenge.expires_at,
+                }),
+                Err(err) => {
+                    error!("error creating login challenge: {}", err);
+                    HttpResponse::InternalServerError()
+                        .body("Error trying to create login challenge")
+                }
+            };
+        }
+        Ok(_) => {}
+        Err(err) => {
+            error!("error fetching totp credential: {}", err);
+            return HttpResponse::InternalServerError()
+                .body("Error trying to read two-factor settings from database");
+        }
+    }
+
     let token = Token::create(user.id, db_pool.get_ref()).await;
     match token {
-        Ok(token) => HttpResponse::Ok().json(token),
+        Ok(token) => {
+            let details = json!({ "method": "password", "token_id": token.id });
+            audit::record(
+                &req,
+                AuditAction::Login,
+                Some(user.id),
+                None,
+                details,
+                db_pool.get_ref(),
+            )
+            .await;
+            HttpResponse::Ok().json(token)
+        }
         Err(err) => {
             error!("error creating token: {}", err);
             HttpResponse::InternalServerError().body("Error trying to create new token")
         }
     }
 }
+
+fn is_valid_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => !local.is_empty() && domain.contains('.'),
+        None => false,
+    }
+}
+
+// mails a verification link for the address currently on the account, if it still needs one
+async fn send_verification(
+    user: &User,
+    pool: &PgPool,
+    mailer: &dyn Mailer,
+    email_settings: &EmailSettings,
+) -> anyhow::Result<()> {
+    let email = match (&user.email, user.email_verified_at) {
+        (Some(email), None) => email,
+        _ => return Ok(()),
+    };
+
+    let ttl = email_settings.verification_ttl;
+    let (_, code) = EmailVerification::create(user.id, email, ttl, pool).await?;
+    let message = Message {
+        to: email.clone(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Open this link to verify your email address: {}",
+            email_settings.verify_link(&code)
+        ),
+    };
+    mailer.send(&message)
+}